@@ -0,0 +1,367 @@
+use std::{ffi::OsStr, str::FromStr};
+
+use anyhow::{bail, Context, Error, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::owner::OwnerMapper;
+
+/// Xattrs exposing the access and default ACL
+pub const ACL_ACCESS_XATTR: &str = "system.posix_acl_access";
+pub const ACL_DEFAULT_XATTR: &str = "system.posix_acl_default";
+
+/// Version of the binary ACL format used by the `system.posix_acl_*` xattrs
+const ACL_XATTR_VERSION: u32 = 2;
+
+/// Id of entries not referring to a specific user or group
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+
+// Entry tags, in the order the kernel expects the entries to be sorted in
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+/// Access and default ACL of a node
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Acls {
+    pub access: Option<Acl>,
+    pub default: Option<Acl>,
+}
+
+impl Acls {
+    pub fn is_empty(&self) -> bool {
+        self.access.is_none() && self.default.is_none()
+    }
+}
+
+/// ACL as stored by `tar --acls`. Users and groups are kept as stored, so that they are mapped
+/// like owners when the archive is mounted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Acl(Vec<AclEntry>);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct AclEntry {
+    tag: u16,
+    perm: u16,
+    qualifier: Qualifier,
+}
+
+/// User or group an entry applies to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+enum Qualifier {
+    /// Owner, owning group, mask or others
+    None,
+    /// Named user or group, identified by its id, its name or both
+    Named {
+        id: Option<u32>,
+        name: Option<String>,
+    },
+}
+
+impl FromStr for Acl {
+    type Err = Error;
+
+    /// Parse an ACL in the textual form stored by `tar --acls`, e.g.
+    /// `user::rw-,user:1000:r--,group::r--,mask::r--,other::r--`.
+    fn from_str(text: &str) -> Result<Self> {
+        let mut entries = Vec::new();
+        for entry in text.split([',', '\n']) {
+            // Entries may be followed by comments such as `#effective:r--`
+            let entry = entry.split('#').next().unwrap_or_default().trim();
+            if entry.is_empty() {
+                continue;
+            }
+            entries
+                .push(parse_entry(entry).with_context(|| format!("Invalid ACL entry: {entry}"))?);
+        }
+        Ok(Self(entries))
+    }
+}
+
+/// Convert `acl` to the binary form of the `system.posix_acl_access` and
+/// `system.posix_acl_default` xattrs, mapping named users and groups with `mapper`.
+pub fn acl_to_xattr(acl: &Acl, mapper: &mut OwnerMapper) -> Result<Vec<u8>> {
+    let mut entries = Vec::with_capacity(acl.0.len());
+    for entry in &acl.0 {
+        let id = match &entry.qualifier {
+            Qualifier::None => ACL_UNDEFINED_ID,
+            Qualifier::Named { id, name } => match entry.tag {
+                ACL_USER => mapper.map_user(*id, name.as_deref().map(OsStr::new)),
+                _ => mapper.map_group(*id, name.as_deref().map(OsStr::new)),
+            }
+            .with_context(|| {
+                format!(
+                    "Unknown user or group: {}",
+                    name.as_deref().unwrap_or_default()
+                )
+            })?,
+        };
+        entries.push((entry.tag, entry.perm, id));
+    }
+    entries.sort_unstable_by_key(|&(tag, _, id)| (tag, id));
+
+    let mut xattr = Vec::with_capacity(4 + 8 * entries.len());
+    xattr.extend_from_slice(&ACL_XATTR_VERSION.to_le_bytes());
+    for (tag, perm, id) in entries {
+        xattr.extend_from_slice(&tag.to_le_bytes());
+        xattr.extend_from_slice(&perm.to_le_bytes());
+        xattr.extend_from_slice(&id.to_le_bytes());
+    }
+    Ok(xattr)
+}
+
+/// Check whether the access ACL `xattr` of a node owned by `owner` grants the permissions in
+/// `mask` to the user `uid`, which is a member of the groups accepted by `in_group`. Follows the
+/// POSIX ACL access check algorithm.
+pub fn acl_permits(
+    xattr: &[u8],
+    owner: (u32, u32),
+    uid: u32,
+    in_group: impl Fn(u32) -> bool,
+    mask: u16,
+) -> bool {
+    let entries = decode_xattr(xattr);
+    let perm = |tag| {
+        entries
+            .iter()
+            .find(|entry| entry.0 == tag)
+            .map(|entry| entry.1)
+    };
+    let acl_mask = perm(ACL_MASK).unwrap_or(0o7);
+
+    if uid == owner.0 {
+        return perm(ACL_USER_OBJ).unwrap_or(0) & mask == mask;
+    }
+    if let Some(&(_, perm, _)) = entries
+        .iter()
+        .find(|&&(tag, _, id)| tag == ACL_USER && id == uid)
+    {
+        return perm & acl_mask & mask == mask;
+    }
+    // Any matching group entry may grant access, but others are only checked if none matches
+    let mut group_matched = false;
+    for &(tag, perm, id) in &entries {
+        let matches = match tag {
+            ACL_GROUP_OBJ => in_group(owner.1),
+            ACL_GROUP => in_group(id),
+            _ => false,
+        };
+        if matches && perm & acl_mask & mask == mask {
+            return true;
+        }
+        group_matched |= matches;
+    }
+    !group_matched && perm(ACL_OTHER).unwrap_or(0) & mask == mask
+}
+
+/// Decode the binary form of an ACL into `(tag, perm, id)` entries. Truncated entries are
+/// ignored.
+fn decode_xattr(xattr: &[u8]) -> Vec<(u16, u16, u32)> {
+    xattr
+        .get(4..)
+        .unwrap_or_default()
+        .chunks_exact(8)
+        .map(|entry| {
+            (
+                u16::from_le_bytes([entry[0], entry[1]]),
+                u16::from_le_bytes([entry[2], entry[3]]),
+                u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]),
+            )
+        })
+        .collect()
+}
+
+/// Parse a single entry. star appends the numeric id to named entries (`user:name:rwx:1000`),
+/// which takes precedence over the name unless owners are resolved by name.
+fn parse_entry(entry: &str) -> Result<AclEntry> {
+    let fields = entry.split(':').collect::<Vec<_>>();
+    let (tag, qualifier, perm, id) = match fields[..] {
+        [tag, qualifier, perm] => (tag, qualifier, perm, None),
+        [tag, qualifier, perm, id] => (tag, qualifier, perm, Some(id)),
+        _ => bail!("Expected 3 or 4 fields"),
+    };
+
+    let named = !qualifier.is_empty();
+    let tag = match (tag, named) {
+        ("user" | "u", false) => ACL_USER_OBJ,
+        ("user" | "u", true) => ACL_USER,
+        ("group" | "g", false) => ACL_GROUP_OBJ,
+        ("group" | "g", true) => ACL_GROUP,
+        ("mask" | "m", _) => ACL_MASK,
+        ("other" | "o", _) => ACL_OTHER,
+        _ => bail!("Unknown tag: {tag}"),
+    };
+
+    let mut bits = 0;
+    for c in perm.chars() {
+        bits |= match c {
+            'r' => 4,
+            'w' => 2,
+            'x' => 1,
+            '-' => 0,
+            _ => bail!("Unknown permission: {c}"),
+        };
+    }
+
+    let qualifier = match (tag, id) {
+        (ACL_USER | ACL_GROUP, Some(id)) => Qualifier::Named {
+            id: Some(id.parse().ok().context("Invalid id")?),
+            name: Some(qualifier.to_string()),
+        },
+        (ACL_USER | ACL_GROUP, None) => match qualifier.parse() {
+            Ok(id) => Qualifier::Named {
+                id: Some(id),
+                name: None,
+            },
+            Err(_) => Qualifier::Named {
+                id: None,
+                name: Some(qualifier.to_string()),
+            },
+        },
+        _ => Qualifier::None,
+    };
+    Ok(AclEntry {
+        tag,
+        perm: bits,
+        qualifier,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::owner::OwnerOptions;
+
+    use super::*;
+
+    fn decode(xattr: &[u8]) -> Vec<(u16, u16, u32)> {
+        assert_eq!(xattr[..4], ACL_XATTR_VERSION.to_le_bytes());
+        assert_eq!(xattr.len() % 8, 4);
+        decode_xattr(xattr)
+    }
+
+    /// Mapper knowing only the user and group `staff` with id 50
+    fn mapper(options: OwnerOptions) -> OwnerMapper {
+        let lookup = |name: &OsStr| (name == "staff").then_some(50);
+        OwnerMapper::with_lookups(options, lookup, lookup)
+    }
+
+    fn convert_with(text: &str, options: OwnerOptions) -> Result<Vec<(u16, u16, u32)>> {
+        Ok(decode(&acl_to_xattr(&text.parse()?, &mut mapper(options))?))
+    }
+
+    fn convert(text: &str) -> Result<Vec<(u16, u16, u32)>> {
+        convert_with(text, OwnerOptions::default())
+    }
+
+    #[test]
+    fn access_acl() {
+        let acl = "user::rw-,user:1000:r--,group::r-x,group:100:rw-,mask::rw-,other::---";
+        assert_eq!(
+            convert(acl).unwrap(),
+            [
+                (ACL_USER_OBJ, 6, ACL_UNDEFINED_ID),
+                (ACL_USER, 4, 1000),
+                (ACL_GROUP_OBJ, 5, ACL_UNDEFINED_ID),
+                (ACL_GROUP, 6, 100),
+                (ACL_MASK, 6, ACL_UNDEFINED_ID),
+                (ACL_OTHER, 0, ACL_UNDEFINED_ID),
+            ]
+        );
+    }
+
+    #[test]
+    fn default_acl() {
+        // Default ACLs are stored without the `default:` prefix, one entry per line, with comments
+        // and in any order
+        let acl =
+            "other::r-x\nmask::rwx\ng:2000:rwx #effective:rwx\nu::rwx\ng::r-x\nu:3:--x\nu:2:r--\n";
+        assert_eq!(
+            convert(acl).unwrap(),
+            [
+                (ACL_USER_OBJ, 7, ACL_UNDEFINED_ID),
+                (ACL_USER, 4, 2),
+                (ACL_USER, 1, 3),
+                (ACL_GROUP_OBJ, 5, ACL_UNDEFINED_ID),
+                (ACL_GROUP, 7, 2000),
+                (ACL_MASK, 7, ACL_UNDEFINED_ID),
+                (ACL_OTHER, 5, ACL_UNDEFINED_ID),
+            ]
+        );
+    }
+
+    #[test]
+    fn named_entries() {
+        // star appends the id, which takes precedence over the name
+        let acl = "user::rw-,user:staff:r--:1234,group::r--,mask::r--,other::r--";
+        assert_eq!(convert(acl).unwrap()[1], (ACL_USER, 4, 1234));
+
+        let acl = "user::rw-,user:staff:r--,group::r--,group:staff:r--,mask::r--,other::r--";
+        let entries = convert(acl).unwrap();
+        assert_eq!(entries[1], (ACL_USER, 4, 50));
+        assert_eq!(entries[3], (ACL_GROUP, 4, 50));
+
+        // Unknown names are kept until the ACL is converted
+        let acl = "user::rw-,user:alice:r--,group::r--,mask::r--,other::r--"
+            .parse::<Acl>()
+            .unwrap();
+        assert!(acl_to_xattr(&acl, &mut mapper(OwnerOptions::default())).is_err());
+    }
+
+    #[test]
+    fn mapped_entries() {
+        let acl =
+            "user::rw-,user:1005:r--,user:7:r--,group::r--,group:1005:r--,mask::r--,other::r--";
+        let options = OwnerOptions {
+            uid_map: vec!["1000:2000:10".parse().unwrap()],
+            gid_map: vec!["1000:3000:10".parse().unwrap()],
+            // Only applies to owners, not to users and groups named in ACLs
+            uid: Some(42),
+            ..Default::default()
+        };
+        let entries = convert_with(acl, options).unwrap();
+        assert_eq!(entries[1], (ACL_USER, 4, 7));
+        assert_eq!(entries[2], (ACL_USER, 4, 2005));
+        assert_eq!(entries[4], (ACL_GROUP, 4, 3005));
+
+        let acl = "user::rw-,user:staff:r--:1234,group::r--,mask::r--,other::r--";
+        let options = OwnerOptions {
+            by_name: true,
+            ..Default::default()
+        };
+        assert_eq!(convert_with(acl, options).unwrap()[1], (ACL_USER, 4, 50));
+    }
+
+    #[test]
+    fn permits() {
+        let acl = "user::rw-,user:1000:rwx,group::r--,group:50:--x,mask::r-x,other::r--";
+        let xattr =
+            acl_to_xattr(&acl.parse().unwrap(), &mut mapper(OwnerOptions::default())).unwrap();
+        let check = |uid, groups: &[u32], mask| {
+            acl_permits(&xattr, (1, 2), uid, |gid| groups.contains(&gid), mask)
+        };
+        // The owner entry is not limited by the mask
+        assert!(check(1, &[], 6));
+        assert!(!check(1, &[], 1));
+        // Named users and groups are
+        assert!(check(1000, &[], 5));
+        assert!(!check(1000, &[], 2));
+        assert!(check(5, &[2], 4));
+        assert!(!check(5, &[2], 1));
+        assert!(check(5, &[50], 1));
+        assert!(check(5, &[2, 50], 1));
+        // Others are only checked if no group matches
+        assert!(!check(5, &[50], 4));
+        assert!(check(5, &[], 4));
+        assert!(!check(5, &[], 1));
+    }
+
+    #[test]
+    fn invalid_entries() {
+        assert!("user::rwz".parse::<Acl>().is_err());
+        assert!("owner::rw-".parse::<Acl>().is_err());
+        assert!("user:rw-".parse::<Acl>().is_err());
+        assert!("user:alice:rw-:alice".parse::<Acl>().is_err());
+    }
+}
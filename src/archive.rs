@@ -2,27 +2,62 @@ use std::{fs::File, io::Read, path::Path};
 
 use anyhow::{bail, Context, Result};
 use bzip2::read::BzDecoder;
-use flate2::read::GzDecoder;
-use tar::Archive;
 use xz::read::XzDecoder;
 
-pub fn open_archive(path: impl AsRef<Path>) -> Result<Archive<Box<dyn Read>>> {
-    let mime_type = infer::get_from_path(&path)
-        .context("Failed to infer archive type")?
-        .context("File type of archive is unknown")?
-        .mime_type();
+use crate::gzip::{Checkpoints, GzReader};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Bzip2,
+    Zstd,
+}
+
+impl Compression {
+    pub fn detect(path: impl AsRef<Path>) -> Result<Self> {
+        let mime_type = infer::get_from_path(&path)
+            .context("Failed to infer archive type")?
+            .context("File type of archive is unknown")?
+            .mime_type();
+
+        let compression = match mime_type {
+            "application/x-tar" => Compression::None,
+            "application/gzip" => Compression::Gzip,
+            "application/x-xz" => Compression::Xz,
+            "application/x-bzip2" => Compression::Bzip2,
+            "application/zstd" => Compression::Zstd,
+            _ => bail!("Unsupported archive or compression type: {mime_type}"),
+        };
+        Ok(compression)
+    }
+}
+
+/// Open the decompressed stream of the archive at `path`. Checkpoints for random access are
+/// recorded to `checkpoints` while a gzip compressed archive is being read.
+pub fn open_stream(
+    path: impl AsRef<Path>,
+    checkpoints: Option<&Checkpoints>,
+) -> Result<Box<dyn Read>> {
+    let compression = Compression::detect(&path)?;
 
     let archive = File::open(&path).context("Failed to open archive")?;
-    let decompressor: Box<dyn Read> = match mime_type {
-        "application/x-tar" => Box::new(archive),
-        "application/gzip" => Box::new(GzDecoder::new(archive)),
-        "application/x-xz" => Box::new(XzDecoder::new(archive)),
-        "application/x-bzip2" => Box::new(BzDecoder::new(archive)),
-        "application/zstd" => {
+    let decompressor: Box<dyn Read> = match compression {
+        Compression::None => Box::new(archive),
+        Compression::Gzip => Box::new(
+            match checkpoints {
+                Some(checkpoints) => GzReader::with_checkpoints(archive, checkpoints.clone()),
+                None => GzReader::new(archive),
+            }
+            .context("Failed to create gzip decoder")?,
+        ),
+        Compression::Xz => Box::new(XzDecoder::new_multi_decoder(archive)),
+        Compression::Bzip2 => Box::new(BzDecoder::new(archive)),
+        Compression::Zstd => {
             Box::new(zstd::Decoder::new(archive).context("Failed to create zstd decoder")?)
         }
-        _ => bail!("Unsupported archive or compression type: {mime_type}"),
     };
 
-    Ok(tar::Archive::new(decompressor))
+    Ok(decompressor)
 }
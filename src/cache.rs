@@ -1,40 +1,126 @@
 use std::{
-    fs::{create_dir_all, remove_dir_all, File},
-    io::{Read, Seek, SeekFrom},
-    os::unix::ffi::OsStrExt,
+    fs::{remove_dir_all, remove_file, rename, symlink_metadata, DirBuilder, File},
+    io::{self, Read, Seek, SeekFrom},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{DirBuilderExt, MetadataExt},
+    },
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
 };
 
-use anyhow::{bail, Context, Result};
-use tar::Archive;
+use anyhow::{bail, ensure, Context, Result};
 
-use crate::archive::open_archive;
+use crate::{
+    archive::{open_stream, Compression},
+    gzip::{self, Checkpoint},
+    node::Node,
+    xz_index::BlockIndex,
+    zstd_seekable::SeekTable,
+};
+
+/// Directory of the indexes and cached files of the current user, which must not be shared with
+/// other users as they could plant indexes and cached files otherwise
+pub fn base_dir() -> PathBuf {
+    match std::env::var_os("XDG_CACHE_HOME").map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => dir.join("tarfs"),
+        _ => PathBuf::from(format!("/var/tmp/tarfs-{}", euid())),
+    }
+}
+
+/// Create the directory at `path` with access for the current user only, or check that it
+/// exists as such a directory.
+pub fn create_private_dir(path: &Path) -> Result<()> {
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(path)
+        .with_context(|| format!("Failed to create directory: {}", path.display()))?;
+    let meta = symlink_metadata(path)
+        .with_context(|| format!("Failed to get metadata of {}", path.display()))?;
+    ensure!(meta.is_dir(), "Not a directory: {}", path.display());
+    check_private(&meta).with_context(|| format!("Refusing to use {}", path.display()))
+}
+
+/// Check that a file is owned by the current user and not writable by anyone else.
+pub fn check_private(meta: &std::fs::Metadata) -> Result<()> {
+    ensure!(meta.uid() == euid(), "Owned by another user");
+    ensure!(meta.mode() & 0o022 == 0, "Writable by other users");
+    Ok(())
+}
+
+fn euid() -> u32 {
+    // SAFETY: geteuid has no preconditions and cannot fail
+    unsafe { libc::geteuid() }
+}
+
+/// Counter to give temporary cache files unique names, together with the process id
+static NEXT_TMP_ID: AtomicU64 = AtomicU64::new(0);
 
 pub struct EntryCache {
     archive_path: PathBuf,
+    compression: Compression,
+    checkpoints: Vec<Checkpoint>,
+    seek_table: Option<SeekTable>,
+    block_index: Option<BlockIndex>,
     base_dir: PathBuf,
+    /// Decompressed archive stream and its position. Kept open so that misses in archive order
+    /// are served in a single pass.
+    stream: Option<(Box<dyn Read>, u64)>,
+    prefetcher: Option<Prefetcher>,
 }
 
 impl EntryCache {
-    pub fn new(archive_path: PathBuf, base_dir: impl AsRef<Path>) -> Self {
+    pub fn new(
+        archive_path: PathBuf,
+        compression: Compression,
+        checkpoints: Vec<Checkpoint>,
+        base_dir: impl AsRef<Path>,
+    ) -> Self {
         let base_dir = base_dir.as_ref().join(hash_path(&archive_path));
+        let seek_table = match compression {
+            Compression::Zstd => match SeekTable::read(&archive_path) {
+                Ok(seek_table) => seek_table,
+                Err(error) => {
+                    log::warn!("Ignoring invalid zstd seek table: {error:?}");
+                    None
+                }
+            },
+            _ => None,
+        };
+        let block_index = match compression {
+            Compression::Xz => match BlockIndex::read(&archive_path) {
+                Ok(block_index) => block_index,
+                Err(error) => {
+                    log::warn!("Ignoring invalid xz block index: {error:?}");
+                    None
+                }
+            },
+            _ => None,
+        };
         Self {
             archive_path,
+            compression,
+            checkpoints,
+            seek_table,
+            block_index,
             base_dir,
+            stream: None,
+            prefetcher: None,
         }
     }
 
-    pub fn open(&mut self, path: impl AsRef<Path>) -> Result<File> {
-        if !self.base_dir.exists() {
-            create_dir_all(&self.base_dir).with_context(|| {
-                format!(
-                    "Failed to create cache directory: {}",
-                    self.base_dir.display(),
-                )
-            })?;
-        }
+    pub fn open(&mut self, node: &Node) -> Result<File> {
+        create_cache_dir(&self.base_dir)?;
 
-        let path = path.as_ref();
+        let Node::File { path, offset, .. } = node else {
+            bail!("Only files can be cached: {}", node.path().display());
+        };
+        let size = &node.data_size();
         let cached_path = self.base_dir.join(hash_path(path));
         if cached_path.exists() {
             log::debug!("Cache hit: {}", cached_path.display());
@@ -42,41 +128,207 @@ impl EntryCache {
                 .with_context(|| format!("Failed to open cached file: {}", cached_path.display()));
         }
 
+        // Files not prefetched yet are extracted here rather than waiting for the prefetcher, which
+        // would block all other requests
         log::debug!("Cache miss: {}", cached_path.display());
-        for entry in self
-            .archive()?
-            .entries()
-            .context("Failed to list archive entries")?
-        {
-            let mut entry = entry.context("Failed to read archive entry")?;
-            if entry.path()? == path {
-                let mut file = File::options()
-                    .create_new(true)
-                    .write(true)
-                    .read(true)
-                    .open(&cached_path)
-                    .with_context(|| {
-                        format!("Failed to create cached file: {}", cached_path.display())
-                    })?;
-                std::io::copy(&mut entry, &mut file)?;
-                file.seek(SeekFrom::Start(0))?;
-                return Ok(file);
+        if let Some(reader) = self.reader_at(*offset)? {
+            return write_cached_file(&cached_path, reader, *size);
+        }
+
+        let stream = self.stream_at(*offset)?;
+        let file = write_cached_file(&cached_path, stream, *size);
+        match (&file, &mut self.stream) {
+            (Ok(_), Some((_, position))) => *position += size,
+            _ => self.stream = None,
+        }
+        file
+    }
+
+    /// Extract all files in `nodes` to the cache in a single pass in the background.
+    pub fn prefetch<'a>(&mut self, nodes: impl Iterator<Item = &'a Node>) {
+        let mut files = nodes
+            .filter_map(|node| match node {
+                Node::File { path, offset, .. } => Some((
+                    *offset,
+                    node.data_size(),
+                    self.base_dir.join(hash_path(path)),
+                )),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        files.dedup_by(|a, b| a.2 == b.2);
+
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let archive_path = self.archive_path.clone();
+        let base_dir = self.base_dir.clone();
+        let thread = thread::spawn({
+            let cancelled = cancelled.clone();
+            move || {
+                log::info!("Prefetching {} files", files.len());
+                match prefetch_files(&archive_path, &base_dir, &files, &cancelled) {
+                    Ok(()) => log::info!("Finished prefetching"),
+                    Err(_) if cancelled.load(Ordering::Relaxed) => log::info!("Cancelled prefetch"),
+                    Err(error) => log::error!("{:?}", error.context("Failed to prefetch files")),
+                }
+            }
+        });
+        self.prefetcher = Some(Prefetcher { cancelled, thread });
+    }
+
+    /// Remove all cached files, stopping the prefetcher first so that it does not write new ones.
+    pub fn clean(&mut self) -> std::io::Result<()> {
+        if let Some(prefetcher) = self.prefetcher.take() {
+            prefetcher.cancelled.store(true, Ordering::Relaxed);
+            if prefetcher.thread.join().is_err() {
+                log::error!("Prefetch thread panicked");
             }
         }
+        match remove_dir_all(&self.base_dir) {
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
 
-        bail!("Entry does not exist in archive: {}", path.display());
+    /// Open a reader at `offset` of the decompressed archive if it supports random access.
+    fn reader_at(&self, offset: u64) -> Result<Option<Box<dyn Read>>> {
+        match self.compression {
+            Compression::Gzip if !self.checkpoints.is_empty() => {
+                gzip::reader_at(&self.archive_path, &self.checkpoints, offset).map(Some)
+            }
+            Compression::Zstd => match &self.seek_table {
+                Some(seek_table) => seek_table.reader_at(&self.archive_path, offset).map(Some),
+                None => Ok(None),
+            },
+            Compression::Xz => match &self.block_index {
+                Some(block_index) => block_index.reader_at(&self.archive_path, offset).map(Some),
+                None => Ok(None),
+            },
+            _ => Ok(None),
+        }
     }
 
-    pub fn clean(&self) -> std::io::Result<()> {
-        remove_dir_all(&self.base_dir)
+    /// Advance the decompressed archive stream to `offset`. The stream is only reopened if it
+    /// already went past the offset.
+    fn stream_at(&mut self, offset: u64) -> Result<&mut dyn Read> {
+        if !matches!(&self.stream, Some((_, position)) if *position <= offset) {
+            log::debug!("Opening archive stream");
+            let stream = open_stream(&self.archive_path, None).context("Failed to open archive")?;
+            self.stream = Some((stream, 0));
+        }
+
+        let (stream, position) = self.stream.as_mut().unwrap();
+        if let Err(error) = skip(stream, offset - *position) {
+            self.stream = None;
+            return Err(error);
+        }
+        *position = offset;
+        Ok(self.stream.as_mut().unwrap().0.as_mut())
+    }
+}
+
+struct Prefetcher {
+    cancelled: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+fn prefetch_files(
+    archive_path: &Path,
+    base_dir: &Path,
+    files: &[(u64, u64, PathBuf)],
+    cancelled: &AtomicBool,
+) -> Result<()> {
+    create_cache_dir(base_dir)?;
+
+    let stream = open_stream(archive_path, None).context("Failed to open archive")?;
+    let mut stream = Cancellable {
+        inner: stream,
+        cancelled,
+    };
+    let mut position = 0;
+    for (offset, size, cached_path) in files {
+        if cancelled.load(Ordering::Relaxed) {
+            break;
+        }
+        let Some(gap) = offset.checked_sub(position) else {
+            continue;
+        };
+
+        skip(&mut stream, gap)?;
+        if cached_path.exists() {
+            skip(&mut stream, *size)?;
+        } else {
+            write_cached_file(cached_path, &mut stream, *size)?;
+        }
+        position = offset + size;
+    }
+    Ok(())
+}
+
+/// Reader failing once `cancelled` is set, so that extracting large files stops early
+struct Cancellable<'a, R> {
+    inner: R,
+    cancelled: &'a AtomicBool,
+}
+
+impl<R: Read> Read for Cancellable<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err(io::Error::other("Prefetch cancelled"));
+        }
+        self.inner.read(buf)
     }
+}
+
+/// Copy `size` bytes from `reader` to a new file in the cache. The file is moved into place once
+/// it is complete, so other readers of the cache never see partial files.
+fn write_cached_file(cached_path: &Path, reader: impl Read, size: u64) -> Result<File> {
+    let tmp_id = NEXT_TMP_ID.fetch_add(1, Ordering::Relaxed);
+    let tmp_path = cached_path.with_extension(format!("tmp{}-{tmp_id}", std::process::id()));
+    let mut file = File::options()
+        .create_new(true)
+        .write(true)
+        .read(true)
+        .open(&tmp_path)
+        .with_context(|| format!("Failed to create cached file: {}", tmp_path.display()))?;
+
+    let inner = || -> Result<()> {
+        let count = io::copy(&mut reader.take(size), &mut file)
+            .context("Failed to extract file from archive")?;
+        if count < size {
+            bail!("Archive ended unexpectedly");
+        }
+        rename(&tmp_path, cached_path)
+            .with_context(|| format!("Failed to move cached file: {}", cached_path.display()))?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(())
+    };
+    if let Err(error) = inner() {
+        let _ = remove_file(&tmp_path);
+        return Err(error);
+    }
+    Ok(file)
+}
+
+fn skip(reader: &mut impl Read, count: u64) -> Result<()> {
+    let skipped = io::copy(&mut reader.take(count), &mut io::sink())
+        .context("Failed to read from archive")?;
+    if skipped < count {
+        bail!("Archive ended unexpectedly");
+    }
+    Ok(())
+}
 
-    fn archive(&self) -> Result<Archive<Box<dyn Read>>> {
-        open_archive(&self.archive_path).context("Failed to open archive")
+/// Create the cache directory of an archive at `base_dir` and the directory of the current user
+/// containing it.
+fn create_cache_dir(base_dir: &Path) -> Result<()> {
+    for dir in base_dir.parent().into_iter().chain([base_dir]) {
+        create_private_dir(dir).context("Failed to create cache directory")?;
     }
+    Ok(())
 }
 
-fn hash_path(path: impl AsRef<Path>) -> String {
+pub fn hash_path(path: impl AsRef<Path>) -> String {
     let mut hash = [0; 32];
     blake::hash(256, path.as_ref().as_os_str().as_bytes(), &mut hash).unwrap();
     hex::encode(hash)
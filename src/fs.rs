@@ -1,46 +1,116 @@
 use anyhow::Context;
 use std::{
+    cell::OnceCell,
     collections::HashMap,
+    ffi::OsStr,
     fs::File,
-    io::{Read, Seek, SeekFrom},
-    os::linux::fs::MetadataExt,
-    path::PathBuf,
+    os::unix::ffi::OsStrExt,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
     rc::Rc,
-    time::{SystemTime, UNIX_EPOCH},
 };
 
-use fuser::{Filesystem, FUSE_ROOT_ID};
+use fuser::{consts::FUSE_POSIX_ACL, FileAttr, FileType, Filesystem, FUSE_ROOT_ID};
 
-use crate::{cache::EntryCache, node::Node};
+use crate::{
+    acl::{acl_permits, acl_to_xattr, ACL_ACCESS_XATTR, ACL_DEFAULT_XATTR},
+    archive::Compression,
+    cache::{base_dir, EntryCache},
+    index::Index,
+    node::{Children, Node, Xattrs, BLKSIZE, BLOCK_UNIT},
+    owner::{OwnerMapper, OwnerOptions},
+    sparse::SparseMap,
+};
+
+/// Maximum length of file names the kernel looks up
+const NAME_MAX: u32 = 255;
 
+/// Default time the kernel may cache attributes and names for, as archives never change
 pub const TTL: std::time::Duration = std::time::Duration::from_secs(365 * 24 * 60 * 60);
 
 pub struct ArchiveFs {
+    archive_path: PathBuf,
+    compression: Compression,
     inodes: HashMap<u64, Rc<Node>>,
-    fhs: HashMap<u64, File>,
+    /// Inode of the parent of each directory. The root is its own parent.
+    parents: HashMap<u64, u64>,
+    /// Number of names of nodes with hard links and of directories
+    nlinks: HashMap<u64, u32>,
+    /// Local uid and gid of nodes if owners are remapped
+    owners: HashMap<u64, (u32, u32)>,
+    /// ACLs of nodes in xattr form, with users and groups mapped like owners
+    acls: HashMap<u64, Xattrs>,
+    /// Sizes of directories, computed once like their number of links as they depend on all
+    /// children
+    dir_sizes: HashMap<u64, u64>,
+    /// Sum of the sizes of all files
+    total_size: u64,
+    /// Time the kernel may cache attributes and names for
+    ttl: std::time::Duration,
+    /// Keep the contents of files in the page cache when they are opened again
+    keep_cache: bool,
+    fhs: HashMap<u64, FileHandle>,
     next_fh: u64,
     entry_cache: EntryCache,
 }
 
-fn build_path_map(map: &mut HashMap<String, Rc<Node>>, nodes: &[Rc<Node>]) {
+struct FileHandle {
+    /// Uncompressed archive, or the data of the entry extracted to the entry cache
+    file: File,
+    /// Offset of the data of the entry in `file`
+    offset: u64,
+    /// Apparent size of the file
+    size: u64,
+    sparse: Option<Rc<SparseMap>>,
+}
+
+fn build_path_map<'a>(
+    map: &mut HashMap<PathBuf, Rc<Node>>,
+    nodes: impl IntoIterator<Item = &'a Rc<Node>>,
+) {
     for node in nodes {
-        map.insert(node.path().to_string(), node.clone());
+        map.insert(node.path().to_path_buf(), node.clone());
         if let Node::Directory { children, .. } = node.as_ref() {
             build_path_map(map, children);
         }
     }
 }
 
-fn convert_links(nodes: &[Rc<Node>], path_map: &HashMap<String, Rc<Node>>) -> Vec<Rc<Node>> {
-    let mut converted_nodes = Vec::new();
+/// Follow the hard link to `target`, which may be a hard link itself.
+fn resolve_link(target: &OsStr, path_map: &HashMap<PathBuf, Rc<Node>>) -> Option<Rc<Node>> {
+    let mut target = target;
+    // Bound the number of hops to break cycles
+    for _ in 0..=path_map.len() {
+        match path_map.get(Path::new(target))?.as_ref() {
+            Node::Link {
+                target: next_target,
+                ..
+            } => target = next_target,
+            Node::Directory { .. } => return None,
+            _ => return path_map.get(Path::new(target)).cloned(),
+        }
+    }
+    None
+}
+
+fn convert_links<'a>(
+    nodes: impl IntoIterator<Item = &'a Rc<Node>>,
+    path_map: &HashMap<PathBuf, Rc<Node>>,
+    nlinks: &mut HashMap<u64, u32>,
+) -> Children {
+    let mut converted_nodes = Children::default();
     for node in nodes {
         match node.as_ref() {
-            Node::Link { target, .. } => {
-                let Some(target) = path_map.get(target) else {
-                    log::warn!("Skipping link to unkown target: {target}");
+            Node::Link { name, target, .. } => {
+                let Some(target) = resolve_link(target, path_map) else {
+                    log::warn!(
+                        "Skipping link to unknown target: {}",
+                        Path::new(target).display(),
+                    );
                     continue;
                 };
-                converted_nodes.push(target.clone());
+                *nlinks.entry(target.index()).or_insert(1) += 1;
+                converted_nodes.insert(name.clone(), target);
             }
             Node::Directory {
                 index,
@@ -48,9 +118,17 @@ fn convert_links(nodes: &[Rc<Node>], path_map: &HashMap<String, Rc<Node>>) -> Ve
                 path,
                 mode,
                 mtime,
+                atime,
+                ctime,
+                crtime,
                 uid,
                 gid,
+                uname,
+                gname,
+                xattrs,
+                acls,
                 children,
+                implicit,
             } => {
                 converted_nodes.push(Rc::new(Node::Directory {
                     index: *index,
@@ -58,9 +136,17 @@ fn convert_links(nodes: &[Rc<Node>], path_map: &HashMap<String, Rc<Node>>) -> Ve
                     path: path.clone(),
                     mode: *mode,
                     mtime: *mtime,
+                    atime: *atime,
+                    ctime: *ctime,
+                    crtime: *crtime,
                     uid: *uid,
                     gid: *gid,
-                    children: convert_links(children, path_map),
+                    uname: uname.clone(),
+                    gname: gname.clone(),
+                    xattrs: xattrs.clone(),
+                    acls: acls.clone(),
+                    children: convert_links(children, path_map, nlinks),
+                    implicit: *implicit,
                 }));
             }
             _ => converted_nodes.push(node.clone()),
@@ -69,17 +155,25 @@ fn convert_links(nodes: &[Rc<Node>], path_map: &HashMap<String, Rc<Node>>) -> Ve
     converted_nodes
 }
 
-fn build_inode_map(map: &mut HashMap<u64, Rc<Node>>, nodes: &[Rc<Node>]) {
+fn build_inode_map<'a>(
+    map: &mut HashMap<u64, Rc<Node>>,
+    parents: &mut HashMap<u64, u64>,
+    parent: u64,
+    nodes: impl IntoIterator<Item = &'a Rc<Node>>,
+) {
     for node in nodes {
         match node.as_ref() {
-            Node::File { index, .. } | Node::Symlink { index, .. } => {
+            Node::File { index, .. }
+            | Node::Symlink { index, .. }
+            | Node::Special { index, .. } => {
                 map.insert(*index, node.clone());
             }
             Node::Directory {
                 index, children, ..
             } => {
                 map.insert(*index, node.clone());
-                build_inode_map(map, children);
+                parents.insert(*index, parent);
+                build_inode_map(map, parents, *index, children);
             }
             _ => log::warn!("Skipping unexpected node: {node}"),
         }
@@ -87,59 +181,100 @@ fn build_inode_map(map: &mut HashMap<u64, Rc<Node>>, nodes: &[Rc<Node>]) {
 }
 
 impl ArchiveFs {
-    pub fn new(archive_path: String, root: Vec<Rc<Node>>) -> Self {
+    pub fn new(
+        archive_path: String,
+        compression: Compression,
+        index: Index,
+        prefetch: bool,
+        owner_options: OwnerOptions,
+        ttl: std::time::Duration,
+        keep_cache: bool,
+    ) -> Self {
+        let Index { root, checkpoints } = index;
+
         // Replace links with their targets
         let mut path_map = HashMap::new();
-        build_path_map(&mut path_map, &root);
-        let root = convert_links(&root, &path_map);
-
-        // Add dummy root node
-        let archive_meta = || -> std::io::Result<(SystemTime, u32, u32)> {
-            let meta = PathBuf::from(&archive_path).metadata()?;
-            Ok((meta.modified()?, meta.st_uid(), meta.st_gid()))
-        };
-        let (mtime, uid, gid) = archive_meta().unwrap_or((UNIX_EPOCH, 0, 0));
-        let mut dummy_root_node_children = Vec::with_capacity(2 + root.len());
-        dummy_root_node_children.push(Rc::new(Node::Directory {
-            index: FUSE_ROOT_ID,
-            path: ".".to_string(),
-            name: ".".to_string(),
-            mode: 0o555,
-            mtime,
-            uid: uid as u64,
-            gid: gid as u64,
-            children: Vec::new(),
-        }));
-        dummy_root_node_children.push(Rc::new(Node::Directory {
-            index: 0,
-            path: "..".to_string(),
-            name: "..".to_string(),
-            mode: 0o555,
-            mtime,
-            uid: uid as u64,
-            gid: gid as u64,
-            children: Vec::new(),
-        }));
-        dummy_root_node_children.extend_from_slice(&root);
-        let dummy_root_node = Node::Directory {
-            index: FUSE_ROOT_ID,
-            path: "".to_string(),
-            name: "root".to_string(),
-            mode: 0o555,
-            mtime,
-            uid: uid as u64,
-            gid: gid as u64,
-            children: dummy_root_node_children,
-        };
+        build_path_map(&mut path_map, [&root]);
+        let mut nlinks = HashMap::new();
+        let root = convert_links([&root], &path_map, &mut nlinks);
 
         // Build inode map for fast lookups
         let mut inodes = HashMap::new();
-        inodes.insert(dummy_root_node.index(), Rc::new(dummy_root_node));
-        build_inode_map(&mut inodes, &root);
+        let mut parents = HashMap::new();
+        build_inode_map(&mut inodes, &mut parents, FUSE_ROOT_ID, &root);
+
+        let total_size = inodes
+            .values()
+            .filter(|node| matches!(node.as_ref(), Node::File { .. }))
+            .map(|node| node.attr().size)
+            .sum();
+
+        let mut dir_sizes = HashMap::new();
+        for (ino, node) in &inodes {
+            if let Some((size, nlink)) = node.dir_stats() {
+                dir_sizes.insert(*ino, size);
+                nlinks.insert(*ino, nlink);
+            }
+        }
+
+        let mut owners = HashMap::new();
+        let remap_owners = !owner_options.is_identity();
+        let mut mapper = OwnerMapper::new(owner_options);
+        if remap_owners {
+            // Implicit directories are owned by local users already
+            for (ino, node) in &inodes {
+                if !node.is_implicit() {
+                    owners.insert(*ino, mapper.map(node));
+                }
+            }
+        }
+
+        let mut acls = HashMap::new();
+        for (ino, node) in &inodes {
+            let Some(node_acls) = node.acls().filter(|acls| !acls.is_empty()) else {
+                continue;
+            };
+            let mut xattrs = Xattrs::new();
+            for (name, acl) in [
+                (ACL_ACCESS_XATTR, &node_acls.access),
+                (ACL_DEFAULT_XATTR, &node_acls.default),
+            ] {
+                let Some(acl) = acl else {
+                    continue;
+                };
+                match acl_to_xattr(acl, &mut mapper) {
+                    Ok(xattr) => {
+                        xattrs.insert(name.into(), xattr);
+                    }
+                    Err(err) => log::warn!("Skipping ACL of {}: {err:#}", node.path().display()),
+                }
+            }
+            acls.insert(*ino, xattrs);
+        }
+
+        let mut entry_cache = EntryCache::new(
+            PathBuf::from(&archive_path),
+            compression,
+            checkpoints,
+            base_dir(),
+        );
+        if prefetch && compression != Compression::None {
+            entry_cache.prefetch(inodes.values().map(AsRef::as_ref));
+        }
 
         Self {
-            entry_cache: EntryCache::new(PathBuf::from(&archive_path), "/var/tmp/tarfs"),
+            entry_cache,
+            archive_path: PathBuf::from(archive_path),
+            compression,
             inodes,
+            parents,
+            nlinks,
+            owners,
+            acls,
+            dir_sizes,
+            total_size,
+            ttl,
+            keep_cache,
             fhs: HashMap::new(),
             next_fh: 1,
         }
@@ -148,9 +283,47 @@ impl ArchiveFs {
     fn search(&mut self, inode: u64) -> Option<Rc<Node>> {
         self.inodes.get(&inode).cloned()
     }
+
+    fn attr(&self, node: &Node) -> FileAttr {
+        let mut attr = node.attr();
+        if let Some(size) = self.dir_sizes.get(&attr.ino) {
+            attr.size = *size;
+            attr.blocks = size.div_ceil(BLOCK_UNIT);
+        }
+        if let Some(nlink) = self.nlinks.get(&attr.ino) {
+            attr.nlink = *nlink;
+        }
+        if let Some((uid, gid)) = self.owners.get(&attr.ino) {
+            (attr.uid, attr.gid) = (*uid, *gid);
+        }
+        attr
+    }
+
+    /// Extended attributes of `node`. ACLs converted from `SCHILY.acl.*` records replace raw
+    /// `system.posix_acl_*` records of the same name, which would otherwise be listed twice.
+    fn xattrs<'a>(&'a self, node: &'a Node) -> impl Iterator<Item = (&'a OsStr, &'a [u8])> {
+        let acls = self.acls.get(&node.attr().ino);
+        let raw = node.xattrs().into_iter().flatten();
+        acls.into_iter()
+            .flatten()
+            .chain(raw.filter(move |(name, _)| !acls.is_some_and(|acls| acls.contains_key(*name))))
+            .map(|(name, value)| (name.as_os_str(), value.as_slice()))
+    }
 }
 
 impl Filesystem for ArchiveFs {
+    fn init(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        config: &mut fuser::KernelConfig,
+    ) -> Result<(), libc::c_int> {
+        // Lets the kernel check ACLs when mounted with `--default-permissions`
+        if config.add_capabilities(FUSE_POSIX_ACL).is_err() {
+            log::warn!("Kernel does not support POSIX ACLs, they are only exposed as xattrs");
+        }
+        Ok(())
+    }
+
     fn destroy(&mut self) {
         if let Err(error) = self
             .entry_cache
@@ -174,15 +347,10 @@ impl Filesystem for ArchiveFs {
         };
 
         match node.as_ref() {
-            Node::Directory { children, .. } => {
-                for child in children {
-                    if child.name() == name {
-                        reply.entry(&std::time::Duration::new(0, 0), &child.attr(), 0);
-                        return;
-                    }
-                }
-                reply.error(libc::ENOENT);
-            }
+            Node::Directory { children, .. } => match children.get(name) {
+                Some(child) => reply.entry(&self.ttl, &self.attr(child), 0),
+                None => reply.error(libc::ENOENT),
+            },
             _ => reply.error(libc::ENOTDIR),
         }
     }
@@ -199,7 +367,7 @@ impl Filesystem for ArchiveFs {
             return;
         };
 
-        reply.attr(&std::time::Duration::new(0, 0), &node.attr());
+        reply.attr(&self.ttl, &self.attr(&node));
     }
 
     fn readlink(&mut self, _req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyData) {
@@ -221,7 +389,53 @@ impl Filesystem for ArchiveFs {
         }
     }
 
-    fn open(&mut self, _req: &fuser::Request<'_>, ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
+    fn access(&mut self, req: &fuser::Request<'_>, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
+        let Some(node) = self.search(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+
+        if mask & libc::W_OK != 0 {
+            reply.error(libc::EROFS);
+            return;
+        }
+        let attr = self.attr(&node);
+        let perm = attr.perm as i32;
+        let allowed = if req.uid() == 0 {
+            // Root may read anything and execute anything executable by someone
+            mask & libc::X_OK == 0 || attr.kind == FileType::Directory || perm & 0o111 != 0
+        } else {
+            let groups = OnceCell::new();
+            let in_group = |gid| {
+                req.gid() == gid
+                    || groups
+                        .get_or_init(|| caller_groups(req.pid()))
+                        .contains(&gid)
+            };
+            let acl = self
+                .acls
+                .get(&ino)
+                .and_then(|xattrs| xattrs.get(OsStr::new(ACL_ACCESS_XATTR)));
+            if let Some(acl) = acl {
+                acl_permits(acl, (attr.uid, attr.gid), req.uid(), in_group, mask as u16)
+            } else {
+                let bits = if req.uid() == attr.uid {
+                    perm >> 6
+                } else if in_group(attr.gid) {
+                    perm >> 3
+                } else {
+                    perm
+                };
+                bits & mask == mask
+            }
+        };
+        match allowed {
+            true => reply.ok(),
+            false => reply.error(libc::EACCES),
+        }
+    }
+
+    fn open(&mut self, _req: &fuser::Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
         let node = match self.search(ino) {
             Some(inode) => inode,
             None => {
@@ -230,6 +444,11 @@ impl Filesystem for ArchiveFs {
             }
         };
 
+        if flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0 {
+            reply.error(libc::EROFS);
+            return;
+        }
+
         if matches!(node.as_ref(), Node::Directory { .. }) {
             reply.error(libc::EISDIR);
             return;
@@ -238,12 +457,27 @@ impl Filesystem for ArchiveFs {
         let fh = self.next_fh;
         self.next_fh += 1;
 
-        let file = match self
-            .entry_cache
-            .open(node.path())
-            .context("Failed to open cached file")
-        {
-            Ok(file) => file,
+        let file = match (self.compression, node.as_ref()) {
+            (Compression::None, Node::File { offset, .. }) => File::open(&self.archive_path)
+                .context("Failed to open archive")
+                .map(|archive| (archive, *offset)),
+            _ => self
+                .entry_cache
+                .open(&node)
+                .context("Failed to open cached file")
+                .map(|file| (file, 0)),
+        };
+        let handle = file.map(|(file, offset)| match node.as_ref() {
+            Node::File { size, sparse, .. } => FileHandle {
+                file,
+                offset,
+                size: *size,
+                sparse: sparse.clone(),
+            },
+            _ => unreachable!("Only files can be opened"),
+        });
+        let handle = match handle {
+            Ok(handle) => handle,
             Err(error) => {
                 log::error!("{error:?}");
                 reply.error(libc::EIO);
@@ -251,8 +485,12 @@ impl Filesystem for ArchiveFs {
             }
         };
 
-        self.fhs.insert(fh, file);
-        reply.opened(fh, 0);
+        self.fhs.insert(fh, handle);
+        let flags = match self.keep_cache {
+            true => fuser::consts::FOPEN_KEEP_CACHE,
+            false => 0,
+        };
+        reply.opened(fh, flags);
     }
 
     fn read(
@@ -266,17 +504,24 @@ impl Filesystem for ArchiveFs {
         _lock_owner: Option<u64>,
         reply: fuser::ReplyData,
     ) {
-        let Some(file) = self.fhs.get_mut(&fh) else {
+        let Some(handle) = self.fhs.get(&fh) else {
             reply.error(libc::ENOENT);
             return;
         };
 
-        let mut buf = vec![0; size as usize];
-        let mut inner = || -> std::io::Result<usize> {
-            file.seek(SeekFrom::Start(offset as u64))?;
-            file.read(&mut buf)
+        let count = handle.size.saturating_sub(offset as u64).min(size as u64);
+        let mut buf = vec![0; count as usize];
+        let read_data = |buf: &mut [u8], pos| read_full_at(&handle.file, buf, handle.offset + pos);
+        let result = match &handle.sparse {
+            // Data missing from a truncated archive reads as a hole
+            Some(sparse) => sparse
+                .read_at(&mut buf, offset as u64, |buf, pos| {
+                    read_data(buf, pos).map(|_| ())
+                })
+                .map(|()| buf.len()),
+            None => read_data(&mut buf, offset as u64),
         };
-        match inner().context("Failed to read from cached file") {
+        match result.context("Failed to read from file") {
             Ok(count) => reply.data(&buf[..count]),
             Err(error) => {
                 log::error!("{error:?}");
@@ -285,6 +530,42 @@ impl Filesystem for ArchiveFs {
         }
     }
 
+    fn lseek(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: fuser::ReplyLseek,
+    ) {
+        let Some(handle) = self.fhs.get(&fh) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        // The kernel only asks for data and holes, other seeks are handled without the file system
+        if offset < 0 || !matches!(whence, libc::SEEK_DATA | libc::SEEK_HOLE) {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        if offset as u64 >= handle.size {
+            reply.error(libc::ENXIO);
+            return;
+        }
+
+        let offset = offset as u64;
+        let result = match (whence, &handle.sparse) {
+            (libc::SEEK_DATA, Some(sparse)) => sparse.seek_data(offset),
+            (libc::SEEK_HOLE, Some(sparse)) => Some(sparse.seek_hole(offset, handle.size)),
+            (libc::SEEK_DATA, None) => Some(offset),
+            _ => Some(handle.size),
+        };
+        match result {
+            Some(offset) => reply.offset(offset as i64),
+            None => reply.error(libc::ENXIO),
+        }
+    }
+
     fn release(
         &mut self,
         _req: &fuser::Request<'_>,
@@ -307,22 +588,29 @@ impl Filesystem for ArchiveFs {
         offset: i64,
         mut reply: fuser::ReplyDirectory,
     ) {
-        let node = self.search(ino);
-        let entries = match node.as_deref() {
-            Some(Node::Directory { children, .. }) => children.iter(),
-            Some(_) => {
-                reply.error(libc::ENOTDIR);
-                return;
-            }
-            None => {
-                reply.error(libc::ENOENT);
-                return;
-            }
+        let Some(node) = self.search(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        let Node::Directory { children, .. } = node.as_ref() else {
+            reply.error(libc::ENOTDIR);
+            return;
+        };
+        let Some(parent) = self
+            .parents
+            .get(&ino)
+            .and_then(|parent| self.inodes.get(parent).cloned())
+        else {
+            log::error!("Directory without parent: {}", node.path().display());
+            reply.error(libc::EIO);
+            return;
         };
+        let dots = [(OsStr::new("."), &node), (OsStr::new(".."), &parent)];
+        let entries = dots.into_iter().chain(children.entries());
 
-        for (offset, entry) in entries.enumerate().skip(offset as usize) {
-            let attr = entry.attr();
-            if reply.add(attr.ino, (offset + 1) as i64, attr.kind, entry.name()) {
+        for (offset, (name, entry)) in entries.enumerate().skip(offset as usize) {
+            let attr = self.attr(entry);
+            if reply.add(attr.ino, (offset + 1) as i64, attr.kind, name) {
                 break;
             }
         }
@@ -337,29 +625,43 @@ impl Filesystem for ArchiveFs {
         offset: i64,
         mut reply: fuser::ReplyDirectoryPlus,
     ) {
-        let node = self.search(ino);
-        let entries = match node.as_deref() {
-            Some(Node::Directory { children, .. }) => children.iter(),
-            Some(_) => {
-                reply.error(libc::ENOTDIR);
-                return;
-            }
-            None => {
-                reply.error(libc::ENOENT);
-                return;
-            }
+        let Some(node) = self.search(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        let Node::Directory { children, .. } = node.as_ref() else {
+            reply.error(libc::ENOTDIR);
+            return;
+        };
+        let Some(parent) = self
+            .parents
+            .get(&ino)
+            .and_then(|parent| self.inodes.get(parent).cloned())
+        else {
+            log::error!("Directory without parent: {}", node.path().display());
+            reply.error(libc::EIO);
+            return;
         };
+        let dots = [(OsStr::new("."), &node), (OsStr::new(".."), &parent)];
+        let entries = dots.into_iter().chain(children.entries());
 
-        for (offset, entry) in entries.enumerate().skip(offset as usize) {
-            let attr = entry.attr();
-            if reply.add(attr.ino, (offset + 1) as i64, entry.name(), &TTL, &attr, 0) {
+        for (offset, (name, entry)) in entries.enumerate().skip(offset as usize) {
+            let attr = self.attr(entry);
+            if reply.add(attr.ino, (offset + 1) as i64, name, &self.ttl, &attr, 0) {
                 break;
             }
         }
         reply.ok()
     }
 
-    // TODO: Implement getxattr
+    fn statfs(&mut self, _req: &fuser::Request<'_>, _ino: u64, reply: fuser::ReplyStatfs) {
+        // Nothing can be added to an archive, so there are no free blocks or inodes. The read-only
+        // flag is reported by the kernel based on the mount options.
+        let blocks = self.total_size.div_ceil(BLOCK_UNIT);
+        let files = self.inodes.len() as u64;
+        reply.statfs(blocks, 0, 0, files, 0, BLKSIZE, NAME_MAX, BLOCK_UNIT as u32);
+    }
+
     fn getxattr(
         &mut self,
         _req: &fuser::Request<'_>,
@@ -368,16 +670,18 @@ impl Filesystem for ArchiveFs {
         size: u32,
         reply: fuser::ReplyXattr,
     ) {
-        log::debug!(
-            "[Not Implemented] getxattr(ino: {:#x?}, name: {:?}, size: {})",
-            ino,
-            name,
-            size,
-        );
-        reply.error(libc::ENOSYS);
+        let Some(node) = self.search(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+
+        let value = self.xattrs(&node).find(|(xattr, _)| *xattr == name);
+        match value {
+            Some((_, value)) => reply_xattr(reply, size, value),
+            None => reply.error(libc::ENODATA),
+        }
     }
 
-    // TODO: Implement listxattr
     fn listxattr(
         &mut self,
         _req: &fuser::Request<'_>,
@@ -385,11 +689,159 @@ impl Filesystem for ArchiveFs {
         size: u32,
         reply: fuser::ReplyXattr,
     ) {
-        log::debug!(
-            "[Not Implemented] listxattr(ino: {:#x?}, size: {})",
-            ino,
-            size,
+        let Some(node) = self.search(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+
+        // Names are listed back to back, each terminated by a null byte
+        let mut names = Vec::new();
+        for (name, _) in self.xattrs(&node) {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        reply_xattr(reply, size, &names);
+    }
+}
+
+/// Read into `buf` at `offset` until it is full or the end of `file` is reached. Returns the number
+/// of bytes read.
+fn read_full_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read_at(&mut buf[filled..], offset + filled as u64) {
+            Ok(0) => break,
+            Ok(count) => filled += count,
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(filled)
+}
+
+/// Supplementary groups of the process `pid`, which FUSE requests don't include. The kernel passes
+/// pids as seen from the pid namespace tarfs was mounted from, and 0 for callers outside of it,
+/// whose supplementary groups are then unknown. `/proc` must belong to the same namespace.
+fn caller_groups(pid: u32) -> Vec<u32> {
+    let status = std::fs::read_to_string(format!("/proc/{pid}/status")).unwrap_or_default();
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Groups:"))
+        .map(|groups| groups.split_whitespace().flat_map(str::parse).collect())
+        .unwrap_or_default()
+}
+
+/// Reply with the size of `data` if the caller is probing for it (`size == 0`), or with `data`
+/// itself if it fits into the caller's buffer.
+fn reply_xattr(reply: fuser::ReplyXattr, size: u32, data: &[u8]) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if (size as usize) < data.len() {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tar::{Builder, EntryType, Header};
+
+    use super::*;
+    use crate::{
+        tree::TreeBuilder,
+        util::fixtures::{append_pax_records, tree_options, TempDir},
+    };
+
+    /// File system of the uncompressed `archive`
+    fn archive_fs(dir: &TempDir, archive: Vec<u8>) -> ArchiveFs {
+        let path = dir.write("archive.tar", &archive);
+        let root = TreeBuilder::new(File::open(&path).unwrap(), tree_options())
+            .build()
+            .unwrap();
+        let index = Index {
+            root,
+            checkpoints: Vec::new(),
+        };
+        let path = path.into_os_string().into_string().unwrap();
+        let owner_options = OwnerOptions::default();
+        ArchiveFs::new(
+            path,
+            Compression::None,
+            index,
+            false,
+            owner_options,
+            TTL,
+            true,
+        )
+    }
+
+    fn header(entry_type: EntryType, mode: u32) -> Header {
+        let mut header = Header::new_ustar();
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header.set_size(0);
+        header
+    }
+
+    #[test]
+    fn root_entry_describes_mount_root() {
+        let mut builder = Builder::new(Vec::new());
+        let mut dir = header(EntryType::Directory, 0o1777);
+        builder.append_data(&mut dir, "./", &[][..]).unwrap();
+        let mut file = header(EntryType::Regular, 0o644);
+        builder.append_data(&mut file, "./file", &[][..]).unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        let dir = TempDir::new("fs-root-entry");
+        let fs = archive_fs(&dir, archive.clone());
+        let root = fs.attr(&fs.inodes[&FUSE_ROOT_ID]);
+        assert_eq!(root.kind, FileType::Directory);
+        assert_eq!(root.perm, 0o1777);
+        assert_eq!(fs.parents[&FUSE_ROOT_ID], FUSE_ROOT_ID);
+
+        // Without a root entry, the root is an implicit directory
+        let fs = archive_fs(&dir, archive[512..].to_vec());
+        assert_eq!(fs.inodes.len(), 2);
+        let root = fs.attr(&fs.inodes[&FUSE_ROOT_ID]);
+        assert_eq!(root.perm, tree_options().implicit_dir_mode as u16);
+        assert_eq!(root.nlink, 2);
+    }
+
+    #[test]
+    fn converted_acls_replace_raw_acl_xattrs() {
+        let mut builder = Builder::new(Vec::new());
+        append_pax_records(
+            &mut builder,
+            &[
+                ("SCHILY.xattr.system.posix_acl_access", b"raw"),
+                ("SCHILY.xattr.system.posix_acl_default", b"raw"),
+                ("SCHILY.xattr.user.comment", b"kept"),
+                ("SCHILY.acl.access", b"user::rw-,group::r--,other::r--"),
+            ],
+        );
+        let mut header = header(EntryType::Regular, 0o644);
+        builder.append_data(&mut header, "file", &[][..]).unwrap();
+
+        let dir = TempDir::new("fs-acl-xattrs");
+        let fs = archive_fs(&dir, builder.into_inner().unwrap());
+        let node = fs
+            .inodes
+            .values()
+            .find(|node| node.path() == "file")
+            .unwrap();
+        let xattrs = fs.xattrs(node).collect::<Vec<_>>();
+        let names = xattrs.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+        assert_eq!(names, [ACL_ACCESS_XATTR, ACL_DEFAULT_XATTR, "user.comment"]);
+        // The access ACL is converted while the raw default ACL is kept as there is no other
+        assert_eq!(
+            xattrs[0].1,
+            fs.acls[&node.attr().ino][OsStr::new(ACL_ACCESS_XATTR)]
         );
-        reply.error(libc::ENOSYS);
+        assert_eq!(xattrs[1].1, b"raw");
+        assert_eq!(xattrs[2].1, b"kept");
     }
 }
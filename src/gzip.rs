@@ -0,0 +1,509 @@
+use std::{
+    cell::RefCell,
+    ffi::c_int,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    mem::size_of,
+    path::Path,
+    rc::Rc,
+};
+
+use anyhow::{Context, Result};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
+use libz_sys as z;
+use serde::{Deserialize, Serialize};
+
+/// Size of the deflate history window
+const WINDOW_SIZE: usize = 32 * 1024;
+
+/// Size of the buffer for compressed input
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Window bits for raw deflate streams
+const RAW_WINDOW_BITS: c_int = -15;
+
+/// Window bits for gzip or zlib streams with automatic header detection
+const AUTO_WINDOW_BITS: c_int = 15 + 32;
+
+/// Length of the gzip member trailer (crc32 and size)
+const TRAILER_SIZE: usize = 8;
+
+/// Position in a gzip stream at which decompression can be resumed
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Offset in the decompressed stream
+    out: u64,
+    /// Offset of the first full byte in the compressed stream
+    input: u64,
+    /// Number of bits of the preceding byte which belong to the checkpoint
+    bits: u8,
+    /// Last 32 KiB of decompressed data before the checkpoint, deflate compressed
+    window: Vec<u8>,
+}
+
+/// Shared list of checkpoints recorded while a gzip stream is being decompressed
+#[derive(Clone)]
+pub struct Checkpoints {
+    span: u64,
+    points: Rc<RefCell<Vec<Checkpoint>>>,
+}
+
+impl Checkpoints {
+    /// Record a checkpoint every `span` bytes of decompressed data.
+    pub fn new(span: u64) -> Self {
+        Self {
+            span,
+            points: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    pub fn take(&self) -> Vec<Checkpoint> {
+        self.points.take()
+    }
+}
+
+/// Tracks the decompression history to snapshot windows for new checkpoints
+struct Recorder {
+    checkpoints: Checkpoints,
+    history: Vec<u8>,
+    history_pos: usize,
+    last: Option<u64>,
+}
+
+impl Recorder {
+    fn new(checkpoints: Checkpoints) -> Self {
+        Self {
+            checkpoints,
+            history: Vec::with_capacity(WINDOW_SIZE),
+            history_pos: 0,
+            last: None,
+        }
+    }
+
+    fn push(&mut self, mut data: &[u8]) {
+        if data.len() >= WINDOW_SIZE {
+            data = &data[data.len() - WINDOW_SIZE..];
+        }
+        if self.history.len() < WINDOW_SIZE {
+            let count = data.len().min(WINDOW_SIZE - self.history.len());
+            self.history.extend_from_slice(&data[..count]);
+            data = &data[count..];
+        }
+        for byte in data {
+            self.history[self.history_pos] = *byte;
+            self.history_pos = (self.history_pos + 1) % WINDOW_SIZE;
+        }
+    }
+
+    fn record(&mut self, out: u64, input: u64, bits: u8) -> io::Result<()> {
+        if self
+            .last
+            .is_some_and(|last| out - last < self.checkpoints.span)
+        {
+            return Ok(());
+        }
+
+        let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(&self.history[self.history_pos..])?;
+        encoder.write_all(&self.history[..self.history_pos])?;
+        self.checkpoints.points.borrow_mut().push(Checkpoint {
+            out,
+            input,
+            bits,
+            window: encoder.finish()?,
+        });
+        self.last = Some(out);
+        Ok(())
+    }
+}
+
+/// Owned zlib inflate stream
+struct Inflater {
+    // zlib keeps a pointer to the stream in its internal state, so it must never move
+    stream: Box<z::z_stream>,
+}
+
+unsafe extern "C" fn zalloc(_opaque: z::voidpf, items: z::uInt, size: z::uInt) -> z::voidpf {
+    libc::calloc(items as usize, size as usize)
+}
+
+unsafe extern "C" fn zfree(_opaque: z::voidpf, address: z::voidpf) {
+    libc::free(address)
+}
+
+fn check(ret: c_int, what: &str) -> io::Result<c_int> {
+    match ret {
+        z::Z_OK | z::Z_STREAM_END | z::Z_BUF_ERROR => Ok(ret),
+        z::Z_NEED_DICT => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{what}: Preset dictionary required"),
+        )),
+        z::Z_DATA_ERROR => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{what}: Invalid compressed data"),
+        )),
+        z::Z_MEM_ERROR => Err(io::Error::new(
+            io::ErrorKind::OutOfMemory,
+            format!("{what}: Out of memory"),
+        )),
+        _ => Err(io::Error::other(format!("{what}: zlib error {ret}"))),
+    }
+}
+
+impl Inflater {
+    fn new(window_bits: c_int) -> io::Result<Self> {
+        let mut stream = Box::new(z::z_stream {
+            next_in: std::ptr::null_mut(),
+            avail_in: 0,
+            total_in: 0,
+            next_out: std::ptr::null_mut(),
+            avail_out: 0,
+            total_out: 0,
+            msg: std::ptr::null_mut(),
+            state: std::ptr::null_mut(),
+            zalloc,
+            zfree,
+            opaque: std::ptr::null_mut(),
+            data_type: 0,
+            adler: 0,
+            reserved: 0,
+        });
+        let ret = unsafe {
+            z::inflateInit2_(
+                stream.as_mut(),
+                window_bits,
+                z::zlibVersion(),
+                size_of::<z::z_stream>() as c_int,
+            )
+        };
+        check(ret, "Failed to initialize inflate stream")?;
+        Ok(Self { stream })
+    }
+
+    fn reset(&mut self, window_bits: c_int) -> io::Result<()> {
+        let ret = unsafe { z::inflateReset2(self.stream.as_mut(), window_bits) };
+        check(ret, "Failed to reset inflate stream").map(|_| ())
+    }
+
+    fn prime(&mut self, bits: u8, value: u8) -> io::Result<()> {
+        let ret = unsafe { z::inflatePrime(self.stream.as_mut(), bits as c_int, value as c_int) };
+        check(ret, "Failed to prime inflate stream").map(|_| ())
+    }
+
+    fn set_dictionary(&mut self, dictionary: &[u8]) -> io::Result<()> {
+        let ret = unsafe {
+            z::inflateSetDictionary(
+                self.stream.as_mut(),
+                dictionary.as_ptr(),
+                dictionary.len() as z::uInt,
+            )
+        };
+        check(ret, "Failed to set inflate dictionary").map(|_| ())
+    }
+}
+
+impl Drop for Inflater {
+    fn drop(&mut self) {
+        unsafe {
+            z::inflateEnd(self.stream.as_mut());
+        }
+    }
+}
+
+/// Gzip decompressor which supports multiple members, resuming from checkpoints and recording
+/// new checkpoints on the fly
+pub struct GzReader<R: Read> {
+    inner: R,
+    inflater: Inflater,
+    input: Vec<u8>,
+    input_pos: usize,
+    input_len: usize,
+    total_in: u64,
+    total_out: u64,
+    raw: bool,
+    finished: bool,
+    recorder: Option<Recorder>,
+}
+
+impl<R: Read> GzReader<R> {
+    pub fn new(inner: R) -> io::Result<Self> {
+        Ok(Self::with_inflater(
+            inner,
+            Inflater::new(AUTO_WINDOW_BITS)?,
+            0,
+            0,
+            false,
+        ))
+    }
+
+    pub fn with_checkpoints(inner: R, checkpoints: Checkpoints) -> io::Result<Self> {
+        let mut reader = Self::new(inner)?;
+        reader.recorder = Some(Recorder::new(checkpoints));
+        Ok(reader)
+    }
+
+    fn with_inflater(
+        inner: R,
+        inflater: Inflater,
+        total_in: u64,
+        total_out: u64,
+        raw: bool,
+    ) -> Self {
+        Self {
+            inner,
+            inflater,
+            input: vec![0; CHUNK_SIZE],
+            input_pos: 0,
+            input_len: 0,
+            total_in,
+            total_out,
+            raw,
+            finished: false,
+            recorder: None,
+        }
+    }
+
+    /// Make sure there is some buffered input. Returns false at the end of the input.
+    fn fill_input(&mut self) -> io::Result<bool> {
+        if self.input_pos < self.input_len {
+            return Ok(true);
+        }
+        self.input_pos = 0;
+        self.input_len = self.inner.read(&mut self.input)?;
+        Ok(self.input_len > 0)
+    }
+
+    /// Prepare for the next gzip member. Returns false at the end of the input.
+    fn next_member(&mut self) -> io::Result<bool> {
+        if self.raw {
+            // Raw inflate leaves the member trailer untouched
+            let mut remaining = TRAILER_SIZE;
+            while remaining > 0 {
+                if !self.fill_input()? {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                let count = remaining.min(self.input_len - self.input_pos);
+                self.input_pos += count;
+                self.total_in += count as u64;
+                remaining -= count;
+            }
+            self.raw = false;
+        }
+
+        if !self.fill_input()? {
+            return Ok(false);
+        }
+        self.inflater.reset(AUTO_WINDOW_BITS)?;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for GzReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.finished || buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            if !self.fill_input()? {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Gzip stream ended unexpectedly",
+                ));
+            }
+
+            let available = self.input_len - self.input_pos;
+            let stream = self.inflater.stream.as_mut();
+            stream.next_in = self.input[self.input_pos..].as_mut_ptr();
+            stream.avail_in = available as z::uInt;
+            stream.next_out = buf.as_mut_ptr();
+            stream.avail_out = buf.len().min(z::uInt::MAX as usize) as z::uInt;
+            let avail_out = stream.avail_out as usize;
+            let ret = unsafe { z::inflate(stream, z::Z_BLOCK) };
+            let ret = check(ret, "Failed to inflate gzip stream")?;
+
+            let consumed = available - stream.avail_in as usize;
+            let produced = avail_out - stream.avail_out as usize;
+            let data_type = stream.data_type;
+            self.input_pos += consumed;
+            self.total_in += consumed as u64;
+            self.total_out += produced as u64;
+
+            if let Some(recorder) = &mut self.recorder {
+                recorder.push(&buf[..produced]);
+
+                // Only block boundaries which are not followed by the end of the stream qualify
+                if data_type & 128 != 0 && data_type & 64 == 0 {
+                    recorder.record(self.total_out, self.total_in, (data_type & 7) as u8)?;
+                }
+            }
+
+            if ret == z::Z_STREAM_END && !self.next_member()? {
+                self.finished = true;
+                return Ok(produced);
+            }
+            if produced > 0 {
+                return Ok(produced);
+            }
+        }
+    }
+}
+
+/// Open a reader at `offset` of the decompressed stream of the gzip file at `path`, resuming from
+/// the closest preceding checkpoint.
+pub fn reader_at(
+    path: impl AsRef<Path>,
+    checkpoints: &[Checkpoint],
+    offset: u64,
+) -> Result<Box<dyn Read>> {
+    let mut file = File::open(&path).context("Failed to open archive")?;
+    let index = checkpoints.partition_point(|checkpoint| checkpoint.out <= offset);
+    let (mut reader, out) = match index.checked_sub(1).map(|index| &checkpoints[index]) {
+        Some(checkpoint) => {
+            let mut inflater =
+                Inflater::new(RAW_WINDOW_BITS).context("Failed to create inflate stream")?;
+            if checkpoint.bits > 0 {
+                file.seek(SeekFrom::Start(checkpoint.input - 1))
+                    .context("Failed to seek to checkpoint")?;
+                let mut byte = [0];
+                file.read_exact(&mut byte)
+                    .context("Failed to read checkpoint")?;
+                inflater
+                    .prime(checkpoint.bits, byte[0] >> (8 - checkpoint.bits))
+                    .context("Failed to restore checkpoint")?;
+            } else {
+                file.seek(SeekFrom::Start(checkpoint.input))
+                    .context("Failed to seek to checkpoint")?;
+            }
+
+            let mut window = Vec::with_capacity(WINDOW_SIZE);
+            DeflateDecoder::new(checkpoint.window.as_slice())
+                .read_to_end(&mut window)
+                .context("Failed to decompress checkpoint window")?;
+            if !window.is_empty() {
+                inflater
+                    .set_dictionary(&window)
+                    .context("Failed to restore checkpoint")?;
+            }
+
+            let reader =
+                GzReader::with_inflater(file, inflater, checkpoint.input, checkpoint.out, true);
+            (reader, checkpoint.out)
+        }
+        None => (
+            GzReader::new(file).context("Failed to create gzip reader")?,
+            0,
+        ),
+    };
+
+    io::copy(&mut (&mut reader).take(offset - out), &mut io::sink())
+        .context("Failed to skip to offset")?;
+    Ok(Box::new(reader))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use flate2::{read::MultiGzDecoder, write::GzEncoder};
+
+    use super::*;
+
+    /// Tar archive of compressible but not trivially repetitive files
+    fn tar_archive() -> Vec<u8> {
+        let words = [
+            "tar",
+            "fuse",
+            "gzip",
+            "inflate",
+            "window",
+            "checkpoint",
+            "member",
+        ];
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut builder = tar::Builder::new(Vec::new());
+        for file in 0..8 {
+            let mut data = Vec::new();
+            while data.len() < 200 * 1024 {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                data.extend_from_slice(words[state as usize % words.len()].as_bytes());
+                data.extend_from_slice(format!("{:x}", state >> 52).as_bytes());
+                data.push(if state.is_multiple_of(11) {
+                    b'\n'
+                } else {
+                    b' '
+                });
+            }
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, format!("file{file}"), data.as_slice())
+                .unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    /// Compress `data` as separate gzip members split at `boundaries`
+    fn multi_member_gzip(data: &[u8], boundaries: &[usize]) -> Vec<u8> {
+        let mut gzip = Vec::new();
+        let mut start = 0;
+        for &end in boundaries.iter().chain([&data.len()]) {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&data[start..end]).unwrap();
+            gzip.extend(encoder.finish().unwrap());
+            start = end;
+        }
+        gzip
+    }
+
+    #[test]
+    fn reader_at_matches_full_decode() {
+        let tar = tar_archive();
+        let boundaries = [tar.len() / 3, tar.len() / 3 * 2];
+        let gzip = multi_member_gzip(&tar, &boundaries);
+        let path = std::env::temp_dir().join(format!("tarfs-gzip-{}.tar.gz", std::process::id()));
+        std::fs::write(&path, &gzip).unwrap();
+
+        let mut expected = Vec::new();
+        MultiGzDecoder::new(gzip.as_slice())
+            .read_to_end(&mut expected)
+            .unwrap();
+        assert_eq!(expected, tar);
+
+        let checkpoints = Checkpoints::new(64 * 1024);
+        let mut decoded = Vec::new();
+        GzReader::with_checkpoints(Cursor::new(&gzip), checkpoints.clone())
+            .unwrap()
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, expected);
+        let checkpoints = checkpoints.take();
+        assert!(checkpoints.len() > 10, "too few checkpoints recorded");
+
+        let mut offsets = vec![0, expected.len() - 1, expected.len()];
+        for checkpoint in &checkpoints {
+            let out = checkpoint.out as usize;
+            offsets.extend([out.saturating_sub(1), out, out + 1]);
+        }
+        for boundary in boundaries {
+            offsets.extend([boundary - 1, boundary, boundary + 1]);
+        }
+        offsets.extend((0..expected.len()).step_by(7919));
+
+        for offset in offsets {
+            let mut reader = reader_at(&path, &checkpoints, offset as u64).unwrap();
+            let end = expected.len().min(offset + 4096);
+            let mut actual = vec![0; end - offset];
+            reader.read_exact(&mut actual).unwrap();
+            assert!(
+                actual == expected[offset..end],
+                "data differs at offset {offset}"
+            );
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    fs::{rename, File},
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::{base_dir, check_private, create_private_dir, hash_path},
+    gzip::Checkpoint,
+    node::Node,
+    tree::TreeOptions,
+};
+
+/// Bump whenever the serialized layout of the index changes
+const INDEX_VERSION: u32 = 14;
+
+/// Number of bytes hashed at the start and at the end of the archive
+const SAMPLE_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Fingerprint {
+    size: u64,
+    mtime: SystemTime,
+    hash: String,
+}
+
+impl Fingerprint {
+    fn of(archive_path: impl AsRef<Path>) -> Result<Self> {
+        let mut archive = File::open(&archive_path).context("Failed to open archive")?;
+        let meta = archive
+            .metadata()
+            .context("Failed to get archive metadata")?;
+        let size = meta.len();
+        let mtime = meta.modified().context("Failed to get archive mtime")?;
+
+        // Hashing the whole archive would take as long as scanning it, so only the head and the
+        // tail are sampled. Together with size and mtime this catches any realistic change.
+        let mut samples = Vec::with_capacity(2 * SAMPLE_SIZE as usize);
+        (&mut archive)
+            .take(SAMPLE_SIZE)
+            .read_to_end(&mut samples)
+            .context("Failed to read archive")?;
+        archive
+            .seek(SeekFrom::Start(size.saturating_sub(SAMPLE_SIZE)))
+            .context("Failed to seek archive")?;
+        archive
+            .read_to_end(&mut samples)
+            .context("Failed to read archive")?;
+        let mut hash = [0; 32];
+        blake::hash(256, &samples, &mut hash).unwrap();
+
+        Ok(Self {
+            size,
+            mtime,
+            hash: hex::encode(hash),
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct IndexHeader {
+    fingerprint: Fingerprint,
+    options: TreeOptions,
+    /// Distance between gzip checkpoints, if the archive is gzip compressed
+    checkpoint_span: Option<u64>,
+}
+
+pub struct Index {
+    /// Root directory of the tree
+    pub root: Rc<Node>,
+    /// Random access checkpoints of gzip compressed archives
+    pub checkpoints: Vec<Checkpoint>,
+}
+
+impl Index {
+    /// Load the index at `index_path` if it exists and still matches the archive, `options` and
+    /// `checkpoint_span`.
+    pub fn load(
+        index_path: impl AsRef<Path>,
+        archive_path: impl AsRef<Path>,
+        options: &TreeOptions,
+        checkpoint_span: Option<u64>,
+    ) -> Result<Option<Self>> {
+        let index_path = index_path.as_ref();
+        if !index_path.exists() {
+            log::debug!("No index at {}", index_path.display());
+            return Ok(None);
+        }
+
+        let file = File::open(index_path)
+            .with_context(|| format!("Failed to open index: {}", index_path.display()))?;
+        // Anyone able to write the index controls the offsets, modes and owners of all nodes
+        let meta = file
+            .metadata()
+            .with_context(|| format!("Failed to get metadata of {}", index_path.display()))?;
+        check_private(&meta)
+            .with_context(|| format!("Refusing to load index: {}", index_path.display()))?;
+        let mut reader = BufReader::new(file);
+        // The version is read on its own as the header layout may change between versions
+        let version: u32 =
+            bincode::deserialize_from(&mut reader).context("Failed to read index version")?;
+        if version != INDEX_VERSION {
+            log::info!("Index version changed, rebuilding index");
+            return Ok(None);
+        }
+        let header: IndexHeader =
+            bincode::deserialize_from(&mut reader).context("Failed to read index header")?;
+        if header.fingerprint != Fingerprint::of(archive_path)? {
+            log::info!("Archive changed, rebuilding index");
+            return Ok(None);
+        }
+        if header.options != *options {
+            log::info!("Tree options changed, rebuilding index");
+            return Ok(None);
+        }
+        if header.checkpoint_span != checkpoint_span {
+            log::info!("Checkpoint span changed, rebuilding index");
+            return Ok(None);
+        }
+
+        let root = bincode::deserialize_from(&mut reader).context("Failed to read index")?;
+        let checkpoints =
+            bincode::deserialize_from(&mut reader).context("Failed to read index checkpoints")?;
+        Ok(Some(Self { root, checkpoints }))
+    }
+
+    /// Write the index to `index_path`, replacing any previous index atomically.
+    pub fn store(
+        &self,
+        index_path: impl AsRef<Path>,
+        archive_path: impl AsRef<Path>,
+        options: &TreeOptions,
+        checkpoint_span: Option<u64>,
+    ) -> Result<()> {
+        let index_path = index_path.as_ref();
+        if let Some(parent) = index_path
+            .parent()
+            .filter(|parent| *parent != Path::new(""))
+        {
+            create_private_dir(parent).context("Failed to create index directory")?;
+        }
+
+        let header = IndexHeader {
+            fingerprint: Fingerprint::of(archive_path)?,
+            options: options.clone(),
+            checkpoint_span,
+        };
+        let mut tmp_path = index_path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let file = File::create(&tmp_path)
+            .with_context(|| format!("Failed to create index: {}", index_path.display()))?;
+        let mut writer = BufWriter::new(file);
+        bincode::serialize_into(&mut writer, &INDEX_VERSION)
+            .context("Failed to write index version")?;
+        bincode::serialize_into(&mut writer, &header).context("Failed to write index header")?;
+        bincode::serialize_into(&mut writer, &self.root).context("Failed to write index")?;
+        bincode::serialize_into(&mut writer, &self.checkpoints)
+            .context("Failed to write index checkpoints")?;
+        writer.flush().context("Failed to write index")?;
+        rename(&tmp_path, index_path)
+            .with_context(|| format!("Failed to move index into place: {}", index_path.display()))
+    }
+}
+
+pub fn default_index_path(archive_path: impl AsRef<Path>) -> PathBuf {
+    let archive_path = archive_path.as_ref();
+    let archive_path = archive_path
+        .canonicalize()
+        .unwrap_or_else(|_| archive_path.to_path_buf());
+    base_dir().join(format!("{}.index", hash_path(archive_path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{set_permissions, Permissions},
+        os::unix::fs::{MetadataExt, PermissionsExt},
+    };
+
+    use crate::{
+        tree::TreeBuilder,
+        util::fixtures::{tree_options, TempDir},
+    };
+
+    use super::*;
+
+    #[test]
+    fn only_private_indexes_are_used() {
+        let dir = TempDir::new("index");
+        let archive_path = dir.write("archive.tar", &[0; 1024]);
+        let index_path = dir.path().join("indexes/archive.index");
+        let root = TreeBuilder::new(&[0; 1024][..], tree_options())
+            .build()
+            .unwrap();
+        let index = Index {
+            root,
+            checkpoints: Vec::new(),
+        };
+        index
+            .store(&index_path, &archive_path, &tree_options(), None)
+            .unwrap();
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().mode() & 0o777;
+        assert_eq!(mode(index_path.parent().unwrap()), 0o700);
+        assert!(
+            Index::load(&index_path, &archive_path, &tree_options(), None)
+                .unwrap()
+                .is_some()
+        );
+
+        set_permissions(&index_path, Permissions::from_mode(0o666)).unwrap();
+        assert!(Index::load(&index_path, &archive_path, &tree_options(), None).is_err());
+
+        set_permissions(dir.path(), Permissions::from_mode(0o777)).unwrap();
+        let shared_path = dir.path().join("archive.index");
+        assert!(index
+            .store(&shared_path, &archive_path, &tree_options(), None)
+            .is_err());
+    }
+}
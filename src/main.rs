@@ -1,16 +1,37 @@
-use anyhow::{Context, Result};
+use std::{
+    os::linux::fs::MetadataExt,
+    path::PathBuf,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::{ensure, Context, Result};
 use camino::Utf8PathBuf;
 use clap::Parser;
-use fs::ArchiveFs;
+use fs::{ArchiveFs, TTL};
 use fuser::MountOption;
 
-use crate::{archive::open_archive, tree::TreeBuilder};
+use crate::{
+    archive::{open_stream, Compression},
+    gzip::Checkpoints,
+    index::{default_index_path, Index},
+    node::{FilenameEncoding, PERMISSION_BITS},
+    owner::{IdMap, OwnerOptions},
+    tree::{TreeBuilder, TreeOptions},
+};
 
+mod acl;
 mod archive;
 mod cache;
 mod fs;
+mod gzip;
+mod index;
 mod node;
+mod owner;
+mod sparse;
 mod tree;
+mod util;
+mod xz_index;
+mod zstd_seekable;
 
 /// Mount a tar archive as a read-only file system
 #[derive(Parser, Debug)]
@@ -28,10 +49,97 @@ struct Args {
     #[clap(long)]
     allow_other: bool,
 
+    /// Let the kernel check access against the permissions stored in the archive
+    #[clap(long)]
+    default_permissions: bool,
+
+    /// Honor device nodes in the archive instead of mounting with nodev
+    #[clap(long)]
+    dev: bool,
+
+    /// Honor setuid and setgid bits in the archive instead of mounting with nosuid
+    #[clap(long)]
+    suid: bool,
+
     /// Dump the file system tree to the debug log
     #[clap(long)]
     dump_tree: bool,
 
+    /// Path of the file system tree index [default: $XDG_CACHE_HOME/tarfs/<hash>.index or
+    /// /var/tmp/tarfs-<UID>/<hash>.index]
+    #[clap(long, value_name = "PATH")]
+    index: Option<String>,
+
+    /// Extract all files of compressed archives to the cache in the background
+    #[clap(long)]
+    prefetch: bool,
+
+    /// Distance between gzip checkpoints in MiB
+    #[clap(
+        long,
+        value_name = "MIB",
+        default_value_t = 16,
+        value_parser = clap::value_parser!(u64).range(1..=1024 * 1024)
+    )]
+    checkpoint_span: u64,
+
+    /// Mode of parent directories missing from the archive (octal)
+    #[clap(long, value_name = "MODE", default_value = "755", value_parser = parse_mode)]
+    implicit_dir_mode: u32,
+
+    /// Mtime of parent directories missing from the archive [default: mtime of the archive]
+    #[clap(long, value_name = "SECONDS")]
+    implicit_dir_mtime: Option<u64>,
+
+    /// Owner of parent directories missing from the archive [default: owner of the archive]
+    #[clap(long, value_name = "UID")]
+    implicit_dir_uid: Option<u64>,
+
+    /// Group of parent directories missing from the archive [default: group of the archive]
+    #[clap(long, value_name = "GID")]
+    implicit_dir_gid: Option<u64>,
+
+    /// Expose entries replaced by later entries with the same path as .<NAME>.~<N>~
+    #[clap(long)]
+    old_versions: bool,
+
+    /// Encoding of file names in the archive
+    #[clap(long, value_name = "ENCODING", value_enum, default_value_t = FilenameEncoding::Raw)]
+    filename_encoding: FilenameEncoding,
+
+    /// Refuse to mount archives with entries escaping the archive root via `..`
+    #[clap(long)]
+    strict: bool,
+
+    /// Present owners by their user and group names on this system if they exist, falling back to
+    /// the ids stored in the archive
+    #[clap(long)]
+    owners_by_name: bool,
+
+    /// Map uids in the archive to local uids, like in /proc/<PID>/uid_map
+    #[clap(long, value_name = "ARCHIVE:LOCAL:COUNT")]
+    uid_map: Vec<IdMap>,
+
+    /// Map gids in the archive to local gids, like in /proc/<PID>/gid_map
+    #[clap(long, value_name = "ARCHIVE:LOCAL:COUNT")]
+    gid_map: Vec<IdMap>,
+
+    /// Owner of all files, overriding the owners stored in the archive
+    #[clap(long, value_name = "UID")]
+    uid: Option<u32>,
+
+    /// Group of all files, overriding the groups stored in the archive
+    #[clap(long, value_name = "GID")]
+    gid: Option<u32>,
+
+    /// Time the kernel may cache attributes and names for in seconds [default: one year]
+    #[clap(long, value_name = "SECONDS")]
+    ttl: Option<u64>,
+
+    /// Drop cached file contents whenever a file is opened
+    #[clap(long)]
+    no_keep_cache: bool,
+
     /// Path to the archive
     #[clap(required = true)]
     archive: String,
@@ -52,15 +160,68 @@ fn main() -> Result<()> {
     let archive_path = Utf8PathBuf::from(args.archive);
     let mount_point = Utf8PathBuf::from(args.mount_point);
 
-    let mut archive = open_archive(&archive_path).context("Failed to open archive")?;
-    let mut tree = TreeBuilder::new(archive.entries().context("Failed to read archive")?);
-    let root = tree.build().context("Failed to build tree from archive")?;
+    let index_path = args
+        .index
+        .map(PathBuf::from)
+        .unwrap_or_else(|| default_index_path(&archive_path));
+
+    let archive_meta = std::fs::metadata(&archive_path).context("Failed to read archive")?;
+    let options = TreeOptions {
+        implicit_dir_mode: args.implicit_dir_mode,
+        implicit_dir_mtime: match args.implicit_dir_mtime {
+            Some(secs) => UNIX_EPOCH + Duration::from_secs(secs),
+            None => archive_meta.modified().unwrap_or(UNIX_EPOCH),
+        },
+        implicit_dir_uid: args
+            .implicit_dir_uid
+            .unwrap_or(archive_meta.st_uid() as u64),
+        implicit_dir_gid: args
+            .implicit_dir_gid
+            .unwrap_or(archive_meta.st_gid() as u64),
+        old_versions: args.old_versions,
+        filename_encoding: args.filename_encoding,
+        strict: args.strict,
+    };
+
+    let compression = Compression::detect(&archive_path)?;
+    // Checkpoints are only recorded for gzip compressed archives
+    let checkpoint_span = args
+        .checkpoint_span
+        .checked_mul(1024 * 1024)
+        .context("Checkpoint span too large")?;
+    let index_span = (compression == Compression::Gzip).then_some(checkpoint_span);
+    let index = match Index::load(&index_path, &archive_path, &options, index_span) {
+        Ok(index) => index,
+        Err(error) => {
+            log::warn!("Ignoring unusable index: {error:?}");
+            None
+        }
+    };
+    let index = match index {
+        Some(index) => {
+            log::info!("Loaded index from {}", index_path.display());
+            index
+        }
+        None => {
+            let checkpoints = Checkpoints::new(checkpoint_span);
+            let stream =
+                open_stream(&archive_path, Some(&checkpoints)).context("Failed to open archive")?;
+            let mut tree = TreeBuilder::new(stream, options.clone());
+            let index = Index {
+                root: tree.build().context("Failed to build tree from archive")?,
+                checkpoints: checkpoints.take(),
+            };
+            match index.store(&index_path, &archive_path, &options, index_span) {
+                Ok(()) => log::info!("Stored index at {}", index_path.display()),
+                Err(error) => log::warn!("Failed to store index: {error:?}"),
+            }
+            index
+        }
+    };
 
     if args.dump_tree {
         let mut tree_buf = vec![b'\n'];
-        for node in &root {
-            node.print_tree(&mut tree_buf)?;
-        }
+        index.root.print_tree(&mut tree_buf)?;
         log::debug!("{}", String::from_utf8_lossy(&tree_buf));
     }
 
@@ -74,9 +235,46 @@ fn main() -> Result<()> {
     if args.allow_other {
         options.push(MountOption::AllowOther);
     }
+    if args.default_permissions {
+        options.push(MountOption::DefaultPermissions);
+    }
+    options.push(if args.dev {
+        MountOption::Dev
+    } else {
+        MountOption::NoDev
+    });
+    options.push(if args.suid {
+        MountOption::Suid
+    } else {
+        MountOption::NoSuid
+    });
 
-    let fs = ArchiveFs::new(archive_path.to_string(), root);
+    let owner_options = OwnerOptions {
+        by_name: args.owners_by_name,
+        uid_map: args.uid_map,
+        gid_map: args.gid_map,
+        uid: args.uid,
+        gid: args.gid,
+    };
+    let fs = ArchiveFs::new(
+        archive_path.to_string(),
+        compression,
+        index,
+        args.prefetch,
+        owner_options,
+        args.ttl.map_or(TTL, Duration::from_secs),
+        !args.no_keep_cache,
+    );
     fuser::mount2(fs, mount_point, &options).context("Failed to mount fuse file system")?;
 
     Ok(())
 }
+
+fn parse_mode(mode: &str) -> Result<u32> {
+    let mode = u32::from_str_radix(mode, 8).context("Mode is not an octal number")?;
+    ensure!(
+        mode & !PERMISSION_BITS == 0,
+        "Mode has bits besides permissions set"
+    );
+    Ok(mode)
+}
@@ -1,86 +1,147 @@
 use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::{OsStr, OsString},
     fmt::Display,
     io::{Read, Write},
     ops::Add,
+    os::unix::ffi::OsStringExt,
+    path::Path,
     rc::Rc,
     time::{Duration, SystemTime},
 };
 
 use anyhow::{Context, Result};
-use camino::Utf8PathBuf;
+use clap::ValueEnum;
 use fuser::{FileAttr, FileType};
+use serde::{Deserialize, Serialize};
 use tar::{Entry, EntryType};
 
-#[derive(Debug)]
+use crate::{
+    acl::{Acl, Acls},
+    sparse::SparseMap,
+    tree::normalize_entry_path,
+};
+
+/// Permission bits of a mode, including the setuid, setgid and sticky bits
+pub const PERMISSION_BITS: u32 = 0o7777;
+
+/// Unit of the number of blocks of a node
+pub const BLOCK_UNIT: u64 = 512;
+
+/// Preferred size of reads, matching the maximum size of FUSE read requests
+pub const BLKSIZE: u32 = 128 * 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
 pub enum Node {
     File {
         index: u64,
-        name: String,
-        path: String,
+        name: OsString,
+        path: OsString,
+        offset: u64,
         size: u64,
+        /// Data chunks of sparse files
+        sparse: Option<Rc<SparseMap>>,
         mode: u32,
         mtime: SystemTime,
+        atime: SystemTime,
+        ctime: SystemTime,
+        crtime: SystemTime,
         uid: u64,
         gid: u64,
+        uname: Option<OsString>,
+        gname: Option<OsString>,
+        xattrs: Xattrs,
+        acls: Acls,
     },
     Directory {
         index: u64,
-        name: String,
-        path: String,
+        name: OsString,
+        path: OsString,
         mode: u32,
         mtime: SystemTime,
+        atime: SystemTime,
+        ctime: SystemTime,
+        crtime: SystemTime,
         uid: u64,
         gid: u64,
-        children: Vec<Rc<Node>>,
+        uname: Option<OsString>,
+        gname: Option<OsString>,
+        xattrs: Xattrs,
+        acls: Acls,
+        children: Children,
+        /// Synthesized because it is missing from the archive, rather than read from an entry
+        implicit: bool,
     },
     Symlink {
         index: u64,
-        name: String,
-        path: String,
+        name: OsString,
+        path: OsString,
+        mtime: SystemTime,
+        atime: SystemTime,
+        ctime: SystemTime,
+        crtime: SystemTime,
+        uid: u64,
+        gid: u64,
+        uname: Option<OsString>,
+        gname: Option<OsString>,
+        xattrs: Xattrs,
+        acls: Acls,
+        target: OsString,
+    },
+    /// Device node or FIFO
+    Special {
+        index: u64,
+        name: OsString,
+        path: OsString,
+        kind: SpecialKind,
+        major: u32,
+        minor: u32,
+        mode: u32,
         mtime: SystemTime,
+        atime: SystemTime,
+        ctime: SystemTime,
+        crtime: SystemTime,
         uid: u64,
         gid: u64,
-        target: String,
+        uname: Option<OsString>,
+        gname: Option<OsString>,
+        xattrs: Xattrs,
+        acls: Acls,
     },
     Link {
         index: u64,
-        name: String,
-        path: String,
-        target: String,
+        name: OsString,
+        path: OsString,
+        target: OsString,
     },
 }
 
 impl Node {
-    pub fn try_from_entry<R: Read>(entry: &'_ Entry<'_, R>, index: u64) -> Result<Option<Self>> {
-        let path_buf = Utf8PathBuf::try_from(
-            entry
-                .path()
-                .context("Failed to get path of entry")?
-                .to_path_buf(),
-        )
-        .context("Entry path is not utf8")?;
-        let name = path_buf
-            .file_name()
-            .context("Failed to get file name of entry")?
-            .to_string();
-        let path = path_buf.to_string();
-        let mode = entry.header().mode().context("Failed to get entry perms")?;
+    /// Create a node for an archive entry at the normalized `path`.
+    pub fn try_from_entry<R: Read>(
+        entry: &'_ mut Entry<'_, R>,
+        index: u64,
+        path: &Path,
+        encoding: FilenameEncoding,
+    ) -> Result<Option<Self>> {
+        // The archive root has no name
+        let name = path.file_name().unwrap_or_default().to_os_string();
+        let path = path.as_os_str().to_os_string();
+        // Some archivers store the file type in the mode as well
+        let mode = entry.header().mode().context("Failed to get entry perms")? & PERMISSION_BITS;
         let uid = entry.header().uid().context("Failed to get entry uid")?;
         let gid = entry.header().gid().context("Failed to get entry gid")?;
-        let mtime = SystemTime::UNIX_EPOCH.add(Duration::from_secs(
-            entry
-                .header()
-                .mtime()
-                .context("Failed to get entry mtime")?,
-        ));
-        let link_target = || -> Result<String> {
-            Ok(entry
-                .link_name()
-                .context("Failed to get link target")?
-                .expect("Link has no target")
-                .to_str()
-                .context("Link target is not utf8")?
-                .to_string())
+        let (uname, gname) = read_owner_names(entry)?;
+        let Timestamps {
+            mtime,
+            atime,
+            ctime,
+            crtime,
+        } = Timestamps::read(entry, Path::new(&path))?;
+        let (xattrs, acls) = read_xattrs(entry, Path::new(&path))?;
+        let link_target = || -> Result<OsString> {
+            let target = entry.link_name_bytes().context("Link has no target")?;
+            Ok(encoding.decode(&target))
         };
         let node = match entry.header().entry_type() {
             EntryType::Directory => Node::Directory {
@@ -89,34 +150,98 @@ impl Node {
                 path,
                 mode,
                 mtime,
+                atime,
+                ctime,
+                crtime,
                 uid,
                 gid,
-                children: Vec::new(),
+                uname,
+                gname,
+                xattrs,
+                acls,
+                children: Children::default(),
+                implicit: false,
             },
-            EntryType::Regular => Node::File {
+            EntryType::Regular | EntryType::GNUSparse => Node::File {
                 index,
                 name,
                 path,
+                offset: entry.raw_file_position(),
                 size: entry.header().size()?,
+                sparse: None,
                 mode,
                 mtime,
+                atime,
+                ctime,
+                crtime,
                 uid,
                 gid,
+                uname,
+                gname,
+                xattrs,
+                acls,
             },
             EntryType::Symlink => Node::Symlink {
                 index,
                 name,
                 path,
                 mtime,
+                atime,
+                ctime,
+                crtime,
                 uid,
                 gid,
+                uname,
+                gname,
+                xattrs,
+                acls,
                 target: link_target()?,
             },
+            entry_type @ (EntryType::Char | EntryType::Block | EntryType::Fifo) => {
+                let kind = match entry_type {
+                    EntryType::Char => SpecialKind::CharDevice,
+                    EntryType::Block => SpecialKind::BlockDevice,
+                    _ => SpecialKind::Fifo,
+                };
+                // FIFOs have no device numbers and archivers may leave the fields blank
+                let (major, minor) = match kind {
+                    SpecialKind::Fifo => (None, None),
+                    _ => (
+                        entry
+                            .header()
+                            .device_major()
+                            .context("Failed to get device major")?,
+                        entry
+                            .header()
+                            .device_minor()
+                            .context("Failed to get device minor")?,
+                    ),
+                };
+                Node::Special {
+                    index,
+                    name,
+                    path,
+                    kind,
+                    major: major.unwrap_or(0),
+                    minor: minor.unwrap_or(0),
+                    mode,
+                    mtime,
+                    atime,
+                    ctime,
+                    crtime,
+                    uid,
+                    gid,
+                    uname,
+                    gname,
+                    xattrs,
+                    acls,
+                }
+            }
             EntryType::Link => Node::Link {
                 index,
                 name,
                 path,
-                target: link_target()?,
+                target: normalize_entry_path(&link_target()?).0.into_os_string(),
             },
             _ => return Ok(None),
         };
@@ -128,25 +253,110 @@ impl Node {
             Node::File { index, .. } => *index,
             Node::Directory { index, .. } => *index,
             Node::Symlink { index, .. } => *index,
+            Node::Special { index, .. } => *index,
             Node::Link { index, .. } => *index,
         }
     }
 
-    pub fn name(&self) -> &str {
+    pub fn name(&self) -> &OsStr {
         match self {
             Node::File { name, .. } => name,
             Node::Directory { name, .. } => name,
             Node::Symlink { name, .. } => name,
+            Node::Special { name, .. } => name,
             Node::Link { name, .. } => name,
         }
     }
 
-    pub fn path(&self) -> &str {
+    /// Change the name and the path of the node, e.g. to expose an older version of an entry.
+    pub fn rename(&mut self, new_name: OsString, new_path: OsString) {
+        let (Node::File { name, path, .. }
+        | Node::Directory { name, path, .. }
+        | Node::Symlink { name, path, .. }
+        | Node::Special { name, path, .. }
+        | Node::Link { name, path, .. }) = self;
+        *name = new_name;
+        *path = new_path;
+    }
+
+    /// Size of the data of a file stored in the archive, which is smaller than the file itself for
+    /// sparse files.
+    pub fn data_size(&self) -> u64 {
+        match self {
+            Node::File {
+                sparse: Some(sparse),
+                ..
+            } => sparse.data_size(),
+            Node::File { size, .. } => *size,
+            _ => 0,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        match self {
+            Node::File { path, .. } => Path::new(path),
+            Node::Directory { path, .. } => Path::new(path),
+            Node::Symlink { path, .. } => Path::new(path),
+            Node::Special { path, .. } => Path::new(path),
+            Node::Link { path, .. } => Path::new(path),
+        }
+    }
+
+    /// Names of the owner and the group of the node, if stored in the archive
+    pub fn owner_names(&self) -> (Option<&OsStr>, Option<&OsStr>) {
+        match self {
+            Node::File { uname, gname, .. }
+            | Node::Directory { uname, gname, .. }
+            | Node::Symlink { uname, gname, .. }
+            | Node::Special { uname, gname, .. } => (uname.as_deref(), gname.as_deref()),
+            Node::Link { .. } => (None, None),
+        }
+    }
+
+    /// Extended attributes of the node. Links have none as they are replaced by their targets.
+    pub fn xattrs(&self) -> Option<&Xattrs> {
+        match self {
+            Node::File { xattrs, .. }
+            | Node::Directory { xattrs, .. }
+            | Node::Symlink { xattrs, .. }
+            | Node::Special { xattrs, .. } => Some(xattrs),
+            Node::Link { .. } => None,
+        }
+    }
+
+    /// Size and number of links of a directory. The size is the size of the FUSE entries of `.`,
+    /// `..` and its children.
+    pub fn dir_stats(&self) -> Option<(u64, u32)> {
+        let Node::Directory { children, .. } = self else {
+            return None;
+        };
+        let names = children.entries().map(|(name, _)| name);
+        let size = [OsStr::new("."), OsStr::new("..")]
+            .into_iter()
+            .chain(names)
+            .map(dirent_size)
+            .sum();
+        // Each subdirectory links back to the directory with its `..` entry
+        let subdirs = children
+            .iter()
+            .filter(|child| matches!(child.as_ref(), Node::Directory { .. }))
+            .count();
+        Some((size, 2 + subdirs as u32))
+    }
+
+    /// Whether the node is a directory missing from the archive
+    pub fn is_implicit(&self) -> bool {
+        matches!(self, Node::Directory { implicit: true, .. })
+    }
+
+    /// ACLs of the node, which are exposed as xattrs once names are resolved
+    pub fn acls(&self) -> Option<&Acls> {
         match self {
-            Node::File { path, .. } => path,
-            Node::Directory { path, .. } => path,
-            Node::Symlink { path, .. } => path,
-            Node::Link { path, .. } => path,
+            Node::File { acls, .. }
+            | Node::Directory { acls, .. }
+            | Node::Symlink { acls, .. }
+            | Node::Special { acls, .. } => Some(acls),
+            Node::Link { .. } => None,
         }
     }
 
@@ -157,72 +367,116 @@ impl Node {
                 size,
                 mode,
                 mtime,
+                atime,
+                ctime,
+                crtime,
                 uid,
                 gid,
                 ..
             } => FileAttr {
                 ino: *index,
                 size: *size,
-                blocks: 0,
-                atime: *mtime,
+                blocks: self.data_size().div_ceil(BLOCK_UNIT),
+                atime: *atime,
                 mtime: *mtime,
-                ctime: *mtime,
-                crtime: *mtime,
+                ctime: *ctime,
+                crtime: *crtime,
                 kind: FileType::RegularFile,
                 perm: *mode as u16,
                 nlink: 1,
                 uid: *uid as u32,
                 gid: *gid as u32,
                 rdev: 0,
-                blksize: 0,
+                blksize: BLKSIZE,
                 flags: 0,
             },
             Node::Directory {
                 index,
                 mode,
                 mtime,
+                atime,
+                ctime,
+                crtime,
+                uid,
+                gid,
+                ..
+            } => {
+                // The size and the number of links depend on all children and are filled in by the
+                // file system
+                FileAttr {
+                    ino: *index,
+                    size: 0,
+                    blocks: 0,
+                    atime: *atime,
+                    mtime: *mtime,
+                    ctime: *ctime,
+                    crtime: *crtime,
+                    kind: FileType::Directory,
+                    perm: *mode as u16,
+                    nlink: 2,
+                    uid: *uid as u32,
+                    gid: *gid as u32,
+                    rdev: 0,
+                    blksize: BLKSIZE,
+                    flags: 0,
+                }
+            }
+            Node::Symlink {
+                index,
+                mtime,
+                atime,
+                ctime,
+                crtime,
                 uid,
                 gid,
+                target,
                 ..
             } => FileAttr {
                 ino: *index,
-                size: 0,
+                size: target.len() as u64,
                 blocks: 0,
-                atime: *mtime,
+                atime: *atime,
                 mtime: *mtime,
-                ctime: *mtime,
-                crtime: *mtime,
-                kind: FileType::Directory,
-                perm: *mode as u16,
+                ctime: *ctime,
+                crtime: *crtime,
+                kind: FileType::Symlink,
+                // Permissions of symlinks are never checked and always reported as 0o777 on Linux
+                perm: 0o777,
                 nlink: 1,
                 uid: *uid as u32,
                 gid: *gid as u32,
                 rdev: 0,
-                blksize: 0,
+                blksize: BLKSIZE,
                 flags: 0,
             },
-            Node::Symlink {
+            Node::Special {
                 index,
+                kind,
+                major,
+                minor,
+                mode,
                 mtime,
+                atime,
+                ctime,
+                crtime,
                 uid,
                 gid,
-                target,
                 ..
             } => FileAttr {
                 ino: *index,
-                size: target.len() as u64,
+                size: 0,
                 blocks: 0,
-                atime: *mtime,
+                atime: *atime,
                 mtime: *mtime,
-                ctime: *mtime,
-                crtime: *mtime,
-                kind: FileType::Symlink,
-                perm: 0o777,
+                ctime: *ctime,
+                crtime: *crtime,
+                kind: kind.file_type(),
+                perm: *mode as u16,
                 nlink: 1,
                 uid: *uid as u32,
                 gid: *gid as u32,
-                rdev: 0,
-                blksize: 0,
+                rdev: encode_dev(*major, *minor),
+                blksize: BLKSIZE,
                 flags: 0,
             },
             Node::Link { .. } => panic!("Can't get file attributes of a link"),
@@ -239,16 +493,34 @@ impl Node {
         }
         match self {
             Node::File { name, .. } => {
-                writeln!(writer, "{name}")?;
+                writeln!(writer, "{}", name.to_string_lossy())?;
             }
             Node::Directory { name, children, .. } => {
-                writeln!(writer, "{name}")?;
+                writeln!(writer, "{}", name.to_string_lossy())?;
                 for child in children {
                     child.print_tree_recursive(writer, indent + 1)?;
                 }
             }
             Node::Symlink { name, target, .. } => {
-                writeln!(writer, "{name} -> {target}")?;
+                writeln!(
+                    writer,
+                    "{} -> {}",
+                    name.to_string_lossy(),
+                    target.to_string_lossy(),
+                )?;
+            }
+            Node::Special {
+                name,
+                kind,
+                major,
+                minor,
+                ..
+            } => {
+                writeln!(
+                    writer,
+                    "{} ({kind:?} {major}:{minor})",
+                    name.to_string_lossy(),
+                )?;
             }
             _ => {}
         }
@@ -256,14 +528,361 @@ impl Node {
     }
 }
 
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum SpecialKind {
+    CharDevice,
+    BlockDevice,
+    Fifo,
+}
+
+impl SpecialKind {
+    fn file_type(self) -> FileType {
+        match self {
+            SpecialKind::CharDevice => FileType::CharDevice,
+            SpecialKind::BlockDevice => FileType::BlockDevice,
+            SpecialKind::Fifo => FileType::NamedPipe,
+        }
+    }
+}
+
+/// Timestamps of an entry. PAX records may carry them with sub-second precision and in addition
+/// to the mtime in the header, which the other timestamps default to.
+struct Timestamps {
+    mtime: SystemTime,
+    atime: SystemTime,
+    ctime: SystemTime,
+    crtime: SystemTime,
+}
+
+impl Timestamps {
+    /// Read the timestamps of the entry at `path`. Invalid PAX timestamps are ignored, so that
+    /// they fall back to the mtime in the header.
+    fn read<R: Read>(entry: &mut Entry<'_, R>, path: &Path) -> Result<Self> {
+        let (mut mtime, mut atime, mut ctime, mut crtime) = (None, None, None, None);
+        if let Some(extensions) = entry
+            .pax_extensions()
+            .context("Failed to read pax extensions")?
+        {
+            for extension in extensions {
+                let extension = extension.context("Failed to read pax extension")?;
+                let time = match extension.key_bytes() {
+                    b"mtime" => &mut mtime,
+                    b"atime" => &mut atime,
+                    b"ctime" => &mut ctime,
+                    b"LIBARCHIVE.creationtime" => &mut crtime,
+                    _ => continue,
+                };
+                let value = String::from_utf8_lossy(extension.value_bytes());
+                match parse_pax_time(&value) {
+                    Some(value) => *time = Some(value),
+                    None => log::warn!(
+                        "Ignoring invalid pax timestamp of {}: {value}",
+                        path.display(),
+                    ),
+                }
+            }
+        }
+        let mtime = match mtime {
+            Some(mtime) => mtime,
+            None => SystemTime::UNIX_EPOCH.add(Duration::from_secs(
+                entry
+                    .header()
+                    .mtime()
+                    .context("Failed to get entry mtime")?,
+            )),
+        };
+        Ok(Self {
+            mtime,
+            atime: atime.unwrap_or(mtime),
+            ctime: ctime.unwrap_or(mtime),
+            crtime: crtime.unwrap_or(mtime),
+        })
+    }
+}
+
+/// Parse a PAX timestamp, i.e. seconds since the epoch with an optional sign and fraction.
+fn parse_pax_time(value: &str) -> Option<SystemTime> {
+    let (negative, value) = match value.strip_prefix('-') {
+        Some(value) => (true, value),
+        None => (false, value),
+    };
+    // Only `[-]digits[.digits]` is valid, which `str::parse` alone doesn't ensure (e.g. `+1`)
+    let is_digits = |s: &str| !s.is_empty() && s.bytes().all(|byte| byte.is_ascii_digit());
+    let (secs, fraction) = match value.split_once('.') {
+        Some((secs, fraction)) => (secs, Some(fraction)),
+        None => (value, None),
+    };
+    if !is_digits(secs) || !fraction.is_none_or(is_digits) {
+        return None;
+    }
+    // Digits beyond nanoseconds are truncated
+    let nanos = fraction
+        .unwrap_or_default()
+        .bytes()
+        .chain(std::iter::repeat(b'0'))
+        .take(9)
+        .fold(0, |nanos, digit| nanos * 10 + u32::from(digit - b'0'));
+    let duration = Duration::new(secs.parse().ok()?, nanos);
+    match negative {
+        true => SystemTime::UNIX_EPOCH.checked_sub(duration),
+        false => SystemTime::UNIX_EPOCH.checked_add(duration),
+    }
+}
+
+/// Read the names of the owner and the group of `entry`. PAX records take precedence over the
+/// header, which can only hold short names.
+fn read_owner_names<R: Read>(
+    entry: &mut Entry<'_, R>,
+) -> Result<(Option<OsString>, Option<OsString>)> {
+    let mut uname = entry.header().username_bytes().map(<[u8]>::to_vec);
+    let mut gname = entry.header().groupname_bytes().map(<[u8]>::to_vec);
+    if let Some(extensions) = entry
+        .pax_extensions()
+        .context("Failed to read pax extensions")?
+    {
+        for extension in extensions {
+            let extension = extension.context("Failed to read pax extension")?;
+            match extension.key_bytes() {
+                b"uname" => uname = Some(extension.value_bytes().to_vec()),
+                b"gname" => gname = Some(extension.value_bytes().to_vec()),
+                _ => {}
+            }
+        }
+    }
+    let name = |name: Option<Vec<u8>>| name.filter(|name| !name.is_empty()).map(OsString::from_vec);
+    Ok((name(uname), name(gname)))
+}
+
+/// Extended attributes by name
+pub type Xattrs = BTreeMap<OsString, Vec<u8>>;
+
+/// Prefix of PAX records holding extended attributes, as written by `tar --xattrs`
+const PAX_XATTR_PREFIX: &[u8] = b"SCHILY.xattr.";
+
+/// PAX records holding textual ACLs, as written by `tar --acls`
+const PAX_ACL_ACCESS: &[u8] = b"SCHILY.acl.access";
+const PAX_ACL_DEFAULT: &[u8] = b"SCHILY.acl.default";
+
+/// Read the extended attributes and ACLs of the entry at `path` from its PAX records
+fn read_xattrs<R: Read>(entry: &mut Entry<'_, R>, path: &Path) -> Result<(Xattrs, Acls)> {
+    let mut xattrs = Xattrs::new();
+    let mut acls = Acls::default();
+    let Some(extensions) = entry
+        .pax_extensions()
+        .context("Failed to read pax extensions")?
+    else {
+        return Ok((xattrs, acls));
+    };
+    for extension in extensions {
+        let extension = extension.context("Failed to read pax extension")?;
+        if let Some(name) = extension.key_bytes().strip_prefix(PAX_XATTR_PREFIX) {
+            xattrs.insert(
+                OsString::from_vec(name.to_vec()),
+                extension.value_bytes().to_vec(),
+            );
+        } else if let key @ (PAX_ACL_ACCESS | PAX_ACL_DEFAULT) = extension.key_bytes() {
+            let acl = extension
+                .value()
+                .ok()
+                .context("ACL is not utf8")
+                .and_then(str::parse::<Acl>);
+            match (acl, key) {
+                (Ok(acl), PAX_ACL_ACCESS) => acls.access = Some(acl),
+                (Ok(acl), _) => acls.default = Some(acl),
+                (Err(err), _) => log::warn!("Skipping ACL of {}: {err:#}", path.display()),
+            }
+        }
+    }
+    Ok((xattrs, acls))
+}
+
+/// Size of the FUSE directory entry of `name`
+fn dirent_size(name: &OsStr) -> u64 {
+    // Inode, offset, name length and type followed by the name, aligned to 8 bytes
+    (24 + name.len() as u64).next_multiple_of(8)
+}
+
+/// Encode a device number the way the kernel expects it from FUSE (`new_encode_dev`)
+fn encode_dev(major: u32, minor: u32) -> u32 {
+    (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)
+}
+
+/// Encoding of file names in an archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum FilenameEncoding {
+    /// Present names as they are stored in the archive
+    Raw,
+    /// Transcode names which are not valid UTF-8 from Latin-1 to UTF-8
+    Latin1,
+}
+
+impl FilenameEncoding {
+    pub fn decode(self, name: &[u8]) -> OsString {
+        match self {
+            FilenameEncoding::Latin1 if std::str::from_utf8(name).is_err() => name
+                .iter()
+                .map(|byte| *byte as char)
+                .collect::<String>()
+                .into(),
+            _ => OsString::from_vec(name.to_vec()),
+        }
+    }
+}
+
 impl Display for Node {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let kind = match self {
             Node::File { .. } => "File",
             Node::Directory { .. } => "Directory",
             Node::Symlink { .. } => "Symlink",
+            Node::Special { .. } => "Special",
             Node::Link { .. } => "Link",
         };
         write!(f, "{kind}")
     }
 }
+
+/// Children of a directory, indexed by name for fast lookups. Children are usually named after
+/// their node, except for hard links which name the node they link to.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(from = "Vec<Rc<Node>>", into = "Vec<Rc<Node>>")]
+pub struct Children {
+    entries: Vec<(OsString, Rc<Node>)>,
+    by_name: HashMap<OsString, usize>,
+}
+
+type ChildNodes<'a> = std::iter::Map<
+    std::slice::Iter<'a, (OsString, Rc<Node>)>,
+    fn(&(OsString, Rc<Node>)) -> &Rc<Node>,
+>;
+
+impl Children {
+    pub fn push(&mut self, node: Rc<Node>) {
+        self.insert(node.name().to_os_string(), node);
+    }
+
+    pub fn insert(&mut self, name: OsString, node: Rc<Node>) {
+        self.by_name.insert(name.clone(), self.entries.len());
+        self.entries.push((name, node));
+    }
+
+    pub fn get(&self, name: &OsStr) -> Option<&Rc<Node>> {
+        self.by_name.get(name).map(|index| &self.entries[*index].1)
+    }
+
+    pub fn iter(&self) -> ChildNodes<'_> {
+        self.entries.iter().map(|(_, node)| node)
+    }
+
+    /// Iterate over the names and nodes of the children
+    pub fn entries(&self) -> impl Iterator<Item = (&OsStr, &Rc<Node>)> {
+        self.entries
+            .iter()
+            .map(|(name, node)| (name.as_os_str(), node))
+    }
+}
+
+impl Extend<Rc<Node>> for Children {
+    fn extend<T: IntoIterator<Item = Rc<Node>>>(&mut self, iter: T) {
+        for node in iter {
+            self.push(node);
+        }
+    }
+}
+
+impl FromIterator<Rc<Node>> for Children {
+    fn from_iter<T: IntoIterator<Item = Rc<Node>>>(iter: T) -> Self {
+        let mut children = Self::default();
+        children.extend(iter);
+        children
+    }
+}
+
+impl From<Vec<Rc<Node>>> for Children {
+    fn from(nodes: Vec<Rc<Node>>) -> Self {
+        nodes.into_iter().collect()
+    }
+}
+
+/// Only trees with unresolved hard links are serialized, so all children are named after their
+/// node.
+impl From<Children> for Vec<Rc<Node>> {
+    fn from(children: Children) -> Self {
+        children.entries.into_iter().map(|(_, node)| node).collect()
+    }
+}
+
+impl<'a> IntoIterator for &'a Children {
+    type Item = &'a Rc<Node>;
+    type IntoIter = ChildNodes<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tar::{Archive, Builder, Header};
+
+    use super::*;
+    use crate::util::fixtures::append_pax_records;
+
+    fn time(secs: i64, nanos: u32) -> SystemTime {
+        let duration = Duration::new(secs.unsigned_abs(), 0);
+        match secs < 0 {
+            true => SystemTime::UNIX_EPOCH - duration + Duration::from_nanos(nanos.into()),
+            false => SystemTime::UNIX_EPOCH + duration + Duration::from_nanos(nanos.into()),
+        }
+    }
+
+    #[test]
+    fn parse_pax_time() {
+        assert_eq!(super::parse_pax_time("0"), Some(time(0, 0)));
+        assert_eq!(
+            super::parse_pax_time("1700000000"),
+            Some(time(1_700_000_000, 0))
+        );
+        assert_eq!(super::parse_pax_time("12.5"), Some(time(12, 500_000_000)));
+        assert_eq!(super::parse_pax_time("12.000000001"), Some(time(12, 1)));
+        // Digits beyond nanoseconds are truncated
+        assert_eq!(
+            super::parse_pax_time("12.1234567899"),
+            Some(time(12, 123_456_789))
+        );
+        assert_eq!(super::parse_pax_time("-5"), Some(time(-5, 0)));
+        assert_eq!(super::parse_pax_time("-1.25"), Some(time(-2, 750_000_000)));
+
+        for invalid in [
+            "", "-", ".5", "12.", "abc", "12.5x", "12.-5", "1.2.3", "--1", "1e5", " 1", "+1",
+            "+123.5", "-+1",
+        ] {
+            assert_eq!(super::parse_pax_time(invalid), None, "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn invalid_pax_time_falls_back_to_header() {
+        let mut builder = Builder::new(Vec::new());
+        append_pax_records(
+            &mut builder,
+            &[
+                ("mtime", b"garbage"),
+                ("atime", b"12.5"),
+                ("ctime", b"+123.5"),
+            ],
+        );
+        let mut header = Header::new_ustar();
+        header.set_mtime(1000);
+        header.set_size(0);
+        builder.append_data(&mut header, "file", &[][..]).unwrap();
+
+        let archive = builder.into_inner().unwrap();
+        let mut archive = Archive::new(archive.as_slice());
+        let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+        let timestamps = Timestamps::read(&mut entry, Path::new("file")).unwrap();
+        assert_eq!(timestamps.mtime, time(1000, 0));
+        assert_eq!(timestamps.atime, time(12, 500_000_000));
+        assert_eq!(timestamps.ctime, time(1000, 0));
+    }
+}
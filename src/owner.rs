@@ -0,0 +1,202 @@
+use std::{
+    collections::HashMap,
+    ffi::{CString, OsStr, OsString},
+    os::unix::ffi::OsStrExt,
+    str::FromStr,
+};
+
+use anyhow::{ensure, Context, Error, Result};
+
+use crate::node::Node;
+
+/// Range of ids in the archive mapped to local ids, like a line of `/proc/<pid>/uid_map`
+#[derive(Debug, Clone, Copy)]
+pub struct IdMap {
+    archive: u32,
+    local: u32,
+    count: u32,
+}
+
+impl IdMap {
+    fn map(&self, id: u32) -> Option<u32> {
+        let offset = id.checked_sub(self.archive)?;
+        (offset < self.count).then(|| self.local + offset)
+    }
+}
+
+impl FromStr for IdMap {
+    type Err = Error;
+
+    /// Parse `<ARCHIVE>:<LOCAL>:<COUNT>`
+    fn from_str(map: &str) -> Result<Self> {
+        let parse = |field: Option<&str>| -> Result<u32> {
+            field
+                .context("Expected <ARCHIVE>:<LOCAL>:<COUNT>")?
+                .parse()
+                .context("Invalid id")
+        };
+        let mut fields = map.split(':');
+        let map = Self {
+            archive: parse(fields.next())?,
+            local: parse(fields.next())?,
+            count: parse(fields.next())?,
+        };
+        ensure!(
+            fields.next().is_none(),
+            "Expected <ARCHIVE>:<LOCAL>:<COUNT>"
+        );
+        ensure!(
+            map.archive.checked_add(map.count).is_some()
+                && map.local.checked_add(map.count).is_some(),
+            "Id range overflows"
+        );
+        Ok(map)
+    }
+}
+
+/// How owners stored in the archive are presented
+#[derive(Debug, Default)]
+pub struct OwnerOptions {
+    /// Resolve owners by name, falling back to the ids stored in the archive
+    pub by_name: bool,
+    pub uid_map: Vec<IdMap>,
+    pub gid_map: Vec<IdMap>,
+    /// Owner of all nodes, overriding everything else
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl OwnerOptions {
+    /// Whether owners are presented as stored in the archive
+    pub fn is_identity(&self) -> bool {
+        !self.by_name
+            && self.uid_map.is_empty()
+            && self.gid_map.is_empty()
+            && self.uid.is_none()
+            && self.gid.is_none()
+    }
+}
+
+/// Maps owners of nodes to local owners, caching name lookups
+pub struct OwnerMapper {
+    options: OwnerOptions,
+    users: HashMap<OsString, Option<u32>>,
+    groups: HashMap<OsString, Option<u32>>,
+    lookup_user: fn(&OsStr) -> Option<u32>,
+    lookup_group: fn(&OsStr) -> Option<u32>,
+}
+
+impl OwnerMapper {
+    pub fn new(options: OwnerOptions) -> Self {
+        Self::with_lookups(options, lookup_user, lookup_group)
+    }
+
+    /// Mapper looking up names with the given functions instead of the users and groups of this
+    /// system
+    pub fn with_lookups(
+        options: OwnerOptions,
+        lookup_user: fn(&OsStr) -> Option<u32>,
+        lookup_group: fn(&OsStr) -> Option<u32>,
+    ) -> Self {
+        Self {
+            options,
+            users: HashMap::new(),
+            groups: HashMap::new(),
+            lookup_user,
+            lookup_group,
+        }
+    }
+
+    /// Local uid and gid of `node`
+    pub fn map(&mut self, node: &Node) -> (u32, u32) {
+        let attr = node.attr();
+        let (uname, gname) = node.owner_names();
+        let uid = match self.options.uid {
+            Some(uid) => uid,
+            None => self.map_user(Some(attr.uid), uname).unwrap_or(attr.uid),
+        };
+        let gid = match self.options.gid {
+            Some(gid) => gid,
+            None => self.map_group(Some(attr.gid), gname).unwrap_or(attr.gid),
+        };
+        (uid, gid)
+    }
+
+    /// Local uid of the user with `id` and `name` in the archive, e.g. a user named in an ACL.
+    /// The name is looked up if there is no id, even when not resolving owners by name.
+    pub fn map_user(&mut self, id: Option<u32>, name: Option<&OsStr>) -> Option<u32> {
+        map_id(
+            id,
+            name.filter(|_| self.options.by_name || id.is_none()),
+            &self.options.uid_map,
+            &mut self.users,
+            self.lookup_user,
+        )
+    }
+
+    /// Local gid of the group with `id` and `name` in the archive, like [`Self::map_user`]
+    pub fn map_group(&mut self, id: Option<u32>, name: Option<&OsStr>) -> Option<u32> {
+        map_id(
+            id,
+            name.filter(|_| self.options.by_name || id.is_none()),
+            &self.options.gid_map,
+            &mut self.groups,
+            self.lookup_group,
+        )
+    }
+}
+
+/// Map `id` by `name` if it is known locally, otherwise by the first matching range in `maps`.
+/// Ids without a match are kept as they are.
+fn map_id(
+    id: Option<u32>,
+    name: Option<&OsStr>,
+    maps: &[IdMap],
+    names: &mut HashMap<OsString, Option<u32>>,
+    lookup: fn(&OsStr) -> Option<u32>,
+) -> Option<u32> {
+    let by_name = name.and_then(|name| {
+        *names
+            .entry(name.to_os_string())
+            .or_insert_with(|| lookup(name))
+    });
+    by_name.or_else(|| id.map(|id| maps.iter().find_map(|map| map.map(id)).unwrap_or(id)))
+}
+
+/// Look up the uid of the user called `name` on this system.
+fn lookup_user(name: &OsStr) -> Option<u32> {
+    let name = CString::new(name.as_bytes()).ok()?;
+    let mut buf = vec![0; 16 * 1024];
+    // SAFETY: All pointers are valid for the duration of the call and the buffer size matches
+    unsafe {
+        let mut passwd = std::mem::zeroed::<libc::passwd>();
+        let mut result = std::ptr::null_mut();
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut passwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        );
+        (!result.is_null()).then_some(passwd.pw_uid)
+    }
+}
+
+/// Look up the gid of the group called `name` on this system.
+fn lookup_group(name: &OsStr) -> Option<u32> {
+    let name = CString::new(name.as_bytes()).ok()?;
+    let mut buf = vec![0; 16 * 1024];
+    // SAFETY: All pointers are valid for the duration of the call and the buffer size matches
+    unsafe {
+        let mut group = std::mem::zeroed::<libc::group>();
+        let mut result = std::ptr::null_mut();
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut group,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        );
+        (!result.is_null()).then_some(group.gr_gid)
+    }
+}
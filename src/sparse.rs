@@ -0,0 +1,400 @@
+use std::{io::Read, str::FromStr};
+
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use tar::{Entry, GnuExtSparseHeader, GnuSparseHeader};
+
+/// Size of tar blocks, which sparse headers and sparse maps are padded to
+const BLOCK_SIZE: usize = 512;
+
+/// Number of digits of the largest `u64`
+const MAX_DIGITS: usize = 20;
+
+/// Chunk of data in a sparse file. Everything between chunks is a hole.
+#[derive(Debug, Serialize, Deserialize)]
+struct Chunk {
+    /// Offset of the chunk in the file
+    offset: u64,
+    length: u64,
+    /// Offset of the chunk in the data stored in the archive
+    data_offset: u64,
+}
+
+impl Chunk {
+    fn end(&self) -> u64 {
+        self.offset + self.length
+    }
+}
+
+/// Data chunks of a sparse file. The chunks are stored back to back in the archive.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SparseMap {
+    chunks: Vec<Chunk>,
+}
+
+/// Sparse file entry in one of the GNU sparse formats
+pub struct SparseEntry {
+    pub map: SparseMap,
+    /// Apparent size of the file
+    pub size: u64,
+    /// Size of the sparse map stored in front of the data (PAX format 1.0)
+    pub map_size: u64,
+    /// Real name of the file (PAX formats)
+    pub name: Option<Vec<u8>>,
+}
+
+impl SparseEntry {
+    /// Read the sparse map of `entry`. Returns `None` if the entry is not a sparse file.
+    /// `ext_headers` holds the extended sparse headers following an old GNU sparse header.
+    pub fn read<R: Read>(entry: &mut Entry<'_, R>, ext_headers: &[u8]) -> Result<Option<Self>> {
+        if entry.header().entry_type().is_gnu_sparse() {
+            return Self::read_gnu(entry, ext_headers).map(Some);
+        }
+
+        let Some(extensions) = entry
+            .pax_extensions()
+            .context("Failed to read pax extensions")?
+        else {
+            return Ok(None);
+        };
+        let mut version = (None, None);
+        let mut name = None;
+        let mut size = None;
+        let mut map = Vec::new();
+        for extension in extensions {
+            let extension = extension.context("Failed to read pax extension")?;
+            let value = || extension.value().ok().context("Pax extension is not utf8");
+            match extension.key_bytes() {
+                b"GNU.sparse.major" => version.0 = Some(parse::<u32>(value()?)?),
+                b"GNU.sparse.minor" => version.1 = Some(parse::<u32>(value()?)?),
+                b"GNU.sparse.name" => name = Some(extension.value_bytes().to_vec()),
+                b"GNU.sparse.realsize" | b"GNU.sparse.size" => size = Some(parse(value()?)?),
+                // Format 0.0 repeats these keys for every chunk
+                b"GNU.sparse.offset" | b"GNU.sparse.numbytes" => map.push(parse(value()?)?),
+                // Format 0.1
+                b"GNU.sparse.map" => {
+                    for number in value()?.split(',').filter(|number| !number.is_empty()) {
+                        map.push(parse(number)?);
+                    }
+                }
+                _ => {}
+            }
+        }
+        let Some(size) = size else {
+            return Ok(None);
+        };
+
+        let mut map_size = 0;
+        match version {
+            (Some(1), Some(0)) => (map, map_size) = read_map(entry)?,
+            (Some(major), Some(minor)) => bail!("Unsupported sparse format {major}.{minor}"),
+            _ => {}
+        }
+        ensure!(map.len() % 2 == 0, "Sparse map has an odd number of values");
+
+        let map = SparseMap::new(map.chunks_exact(2).map(|chunk| (chunk[0], chunk[1])), size)?;
+        Ok(Some(Self {
+            map,
+            size,
+            map_size,
+            name,
+        }))
+    }
+
+    fn read_gnu<R: Read>(entry: &Entry<'_, R>, ext_headers: &[u8]) -> Result<Self> {
+        let gnu = entry
+            .header()
+            .as_gnu()
+            .context("Sparse entry has no GNU header")?;
+        let mut headers = gnu.sparse.iter().collect::<Vec<_>>();
+        let ext_headers = ext_headers
+            .chunks_exact(BLOCK_SIZE)
+            .map(|block| {
+                let mut ext = GnuExtSparseHeader::new();
+                ext.as_mut_bytes().copy_from_slice(block);
+                ext
+            })
+            .collect::<Vec<_>>();
+        if gnu.is_extended() {
+            headers.extend(ext_headers.iter().flat_map(|ext| ext.sparse()));
+        }
+
+        let chunks = headers
+            .into_iter()
+            .filter(|header| !header.is_empty())
+            .map(|header: &GnuSparseHeader| -> Result<(u64, u64)> {
+                Ok((
+                    header.offset().context("Invalid sparse chunk offset")?,
+                    header.length().context("Invalid sparse chunk length")?,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let size = gnu.real_size().context("Invalid sparse file size")?;
+        Ok(Self {
+            map: SparseMap::new(chunks, size)?,
+            size,
+            map_size: 0,
+            name: None,
+        })
+    }
+}
+
+impl SparseMap {
+    fn new(chunks: impl IntoIterator<Item = (u64, u64)>, size: u64) -> Result<Self> {
+        let mut map = Self::default();
+        let mut data_offset = 0;
+        for (offset, length) in chunks {
+            if length == 0 {
+                continue;
+            }
+            let end = offset
+                .checked_add(length)
+                .context("Sparse chunk overflows")?;
+            ensure!(end <= size, "Sparse chunk exceeds the file size");
+            if let Some(last) = map.chunks.last() {
+                ensure!(
+                    offset >= last.end(),
+                    "Sparse chunks overlap or are out of order"
+                );
+            }
+            map.chunks.push(Chunk {
+                offset,
+                length,
+                data_offset,
+            });
+            data_offset += length;
+        }
+        Ok(map)
+    }
+
+    /// Size of the data stored in the archive
+    pub fn data_size(&self) -> u64 {
+        self.chunks
+            .last()
+            .map_or(0, |chunk| chunk.data_offset + chunk.length)
+    }
+
+    /// Fill `buf` with the contents of the file at `offset`. Holes are filled with zeros and data
+    /// is read by `read_data` given its offset in the stored data.
+    pub fn read_at(
+        &self,
+        buf: &mut [u8],
+        offset: u64,
+        mut read_data: impl FnMut(&mut [u8], u64) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        buf.fill(0);
+        let end = offset + buf.len() as u64;
+        let first = self.chunks.partition_point(|chunk| chunk.end() <= offset);
+        for chunk in self.chunks[first..]
+            .iter()
+            .take_while(|chunk| chunk.offset < end)
+        {
+            let start = chunk.offset.max(offset);
+            let stop = chunk.end().min(end);
+            read_data(
+                &mut buf[(start - offset) as usize..(stop - offset) as usize],
+                chunk.data_offset + start - chunk.offset,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Offset of the first data at or after `offset`, if any
+    pub fn seek_data(&self, offset: u64) -> Option<u64> {
+        let first = self.chunks.partition_point(|chunk| chunk.end() <= offset);
+        self.chunks.get(first).map(|chunk| chunk.offset.max(offset))
+    }
+
+    /// Offset of the first hole at or after `offset`. The end of the file counts as a hole.
+    pub fn seek_hole(&self, offset: u64, size: u64) -> u64 {
+        let first = self.chunks.partition_point(|chunk| chunk.end() <= offset);
+        let mut hole = offset;
+        for chunk in &self.chunks[first..] {
+            if chunk.offset > hole {
+                break;
+            }
+            hole = chunk.end();
+        }
+        hole.min(size)
+    }
+}
+
+/// Read the sparse map stored in front of the data of a sparse file in PAX format 1.0. Returns
+/// the offsets and lengths of the chunks and the size of the map including padding.
+fn read_map<R: Read>(entry: &mut Entry<'_, R>) -> Result<(Vec<u64>, u64)> {
+    let entry_size = entry.size();
+    let mut block = [0; BLOCK_SIZE];
+    let mut numbers = Vec::new();
+    let mut number = String::new();
+    let mut map_size = 0;
+    // Number of values following the number of chunks
+    let mut count = None;
+    while count.is_none_or(|count| numbers.len() < count) {
+        entry
+            .read_exact(&mut block)
+            .context("Failed to read sparse map")?;
+        map_size += BLOCK_SIZE as u64;
+        for byte in block {
+            if count.is_some_and(|count| numbers.len() >= count) {
+                break;
+            }
+            if byte != b'\n' {
+                ensure!(
+                    number.len() < MAX_DIGITS,
+                    "Number in sparse map is too long"
+                );
+                number.push(byte as char);
+                continue;
+            }
+            let value = parse::<u64>(&number)?;
+            number.clear();
+            match count {
+                // Every value takes at least two bytes of the entry
+                None => {
+                    let values = value
+                        .checked_mul(2)
+                        .filter(|&values| values <= entry_size / 2)
+                        .context("Sparse map is larger than the entry")?;
+                    count = Some(values as usize);
+                }
+                Some(_) => numbers.push(value),
+            }
+        }
+    }
+    Ok((numbers, map_size))
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T> {
+    value
+        .parse()
+        .ok()
+        .with_context(|| format!("Invalid number in sparse map: {value}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use tar::{Archive, Builder, Header};
+
+    use super::*;
+    use crate::util::fixtures::append_pax_records;
+
+    /// File of size 40 with a hole at the start, adjacent chunks and a hole at the end
+    fn map() -> SparseMap {
+        SparseMap::new([(10, 5), (15, 5), (0, 0), (25, 5)], 40).unwrap()
+    }
+
+    /// Read `len` bytes at `offset` of the file described by `map`, whose stored data is
+    /// `0, 1, 2, ...`
+    fn read(map: &SparseMap, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0xff; len];
+        map.read_at(&mut buf, offset, |buf, data_offset| {
+            for (i, byte) in buf.iter_mut().enumerate() {
+                *byte = data_offset as u8 + i as u8 + 1;
+            }
+            Ok(())
+        })
+        .unwrap();
+        buf
+    }
+
+    #[test]
+    fn new() {
+        let map = map();
+        assert_eq!(map.data_size(), 15);
+        let chunks = map
+            .chunks
+            .iter()
+            .map(|chunk| (chunk.offset, chunk.length, chunk.data_offset))
+            .collect::<Vec<_>>();
+        assert_eq!(chunks, [(10, 5, 0), (15, 5, 5), (25, 5, 10)]);
+
+        assert!(SparseMap::new([(0, 10), (5, 10)], 40).is_err());
+        assert!(SparseMap::new([(20, 5), (0, 5)], 40).is_err());
+        assert!(SparseMap::new([(30, 20)], 40).is_err());
+        assert!(SparseMap::new([(u64::MAX, 2)], u64::MAX).is_err());
+        assert_eq!(SparseMap::new([], 40).unwrap().data_size(), 0);
+    }
+
+    #[test]
+    fn read_at() {
+        let map = map();
+        let mut expected = vec![0; 40];
+        expected[10..20].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        expected[25..30].copy_from_slice(&[11, 12, 13, 14, 15]);
+        assert_eq!(read(&map, 0, 40), expected);
+        for offset in 0..40 {
+            for len in [1, 3, 7, 40 - offset] {
+                let end = (offset + len).min(40);
+                assert_eq!(
+                    read(&map, offset as u64, len)[..end - offset],
+                    expected[offset..end]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn seek_data() {
+        let map = map();
+        assert_eq!(map.seek_data(0), Some(10));
+        assert_eq!(map.seek_data(12), Some(12));
+        assert_eq!(map.seek_data(15), Some(15));
+        assert_eq!(map.seek_data(20), Some(25));
+        assert_eq!(map.seek_data(29), Some(29));
+        assert_eq!(map.seek_data(30), None);
+        assert_eq!(SparseMap::new([], 40).unwrap().seek_data(0), None);
+    }
+
+    #[test]
+    fn seek_hole() {
+        let map = map();
+        assert_eq!(map.seek_hole(0, 40), 0);
+        assert_eq!(map.seek_hole(10, 40), 20);
+        assert_eq!(map.seek_hole(14, 40), 20);
+        assert_eq!(map.seek_hole(20, 40), 20);
+        assert_eq!(map.seek_hole(25, 40), 30);
+        assert_eq!(map.seek_hole(35, 40), 35);
+
+        let full = SparseMap::new([(0, 20), (20, 20)], 40).unwrap();
+        assert_eq!(full.seek_hole(0, 40), 40);
+        assert_eq!(full.seek_hole(39, 40), 40);
+    }
+
+    /// Read the sparse entry of a PAX 1.0 sparse file whose stored data starts with `map`
+    fn read_pax_1_0(map: &str) -> Result<Option<SparseEntry>> {
+        let mut builder = Builder::new(Vec::new());
+        append_pax_records(
+            &mut builder,
+            &[
+                ("GNU.sparse.major", b"1"),
+                ("GNU.sparse.minor", b"0"),
+                ("GNU.sparse.realsize", b"40"),
+            ],
+        );
+
+        let mut data = map.as_bytes().to_vec();
+        data.resize(data.len().next_multiple_of(BLOCK_SIZE) + 15, 0);
+        let mut header = Header::new_ustar();
+        header.set_size(data.len() as u64);
+        builder
+            .append_data(&mut header, "file", data.as_slice())
+            .unwrap();
+
+        let archive = builder.into_inner().unwrap();
+        let mut archive = Archive::new(archive.as_slice());
+        let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+        SparseEntry::read(&mut entry, &[])
+    }
+
+    #[test]
+    fn read_map() {
+        let entry = read_pax_1_0("3\n10\n5\n15\n5\n25\n5\n").unwrap().unwrap();
+        assert_eq!(entry.size, 40);
+        assert_eq!(entry.map_size, BLOCK_SIZE as u64);
+        assert_eq!(entry.map.data_size(), 15);
+
+        assert!(read_pax_1_0("1000\n").is_err());
+        assert!(read_pax_1_0(&format!("{}\n", u64::MAX)).is_err());
+        assert!(read_pax_1_0(&"1".repeat(BLOCK_SIZE)).is_err());
+    }
+}
@@ -1,84 +1,346 @@
-use std::{io::Read, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    io::{self, Read},
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path, PathBuf},
+    rc::Rc,
+    time::SystemTime,
+};
 
-use anyhow::{Context, Result};
-use camino::Utf8PathBuf;
-use tar::{Entries, Entry};
+use anyhow::{bail, Context, Result};
+use fuser::FUSE_ROOT_ID;
+use serde::{Deserialize, Serialize};
+use tar::{Archive, EntryType};
 
-use crate::node::Node;
+use crate::{
+    acl::Acls,
+    node::{Children, FilenameEncoding, Node, Xattrs},
+    sparse::SparseEntry,
+};
 
-pub struct TreeBuilder<'a, R: Read> {
-    entries: Entries<'a, R>,
-    head: Option<Entry<'a, R>>,
+/// Options affecting the tree built from an archive
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TreeOptions {
+    /// Attributes of parent directories missing from the archive
+    pub implicit_dir_mode: u32,
+    pub implicit_dir_mtime: SystemTime,
+    pub implicit_dir_uid: u64,
+    pub implicit_dir_gid: u64,
+    /// Expose entries replaced by later entries with the same path as `.{name}.~{n}~`
+    pub old_versions: bool,
+    pub filename_encoding: FilenameEncoding,
+    /// Refuse archives with entries pointing outside of the archive root
+    pub strict: bool,
+}
+
+pub struct TreeBuilder<R: Read> {
+    archive: Archive<Tap<R>>,
+    tap: Rc<RefCell<TapState>>,
+    options: TreeOptions,
     next_index: u64,
 }
 
-impl<'a, R: Read> TreeBuilder<'a, R> {
-    pub fn new(entries: Entries<'a, R>) -> Self {
+impl<R: Read> TreeBuilder<R> {
+    /// Create a builder for the decompressed archive stream `reader`.
+    pub fn new(reader: R, options: TreeOptions) -> Self {
+        let tap = Rc::new(RefCell::new(TapState::default()));
         Self {
-            entries,
-            head: None,
-            next_index: 1, // Skip fuse root ino (== 1)
+            archive: Archive::new(Tap {
+                inner: reader,
+                state: tap.clone(),
+            }),
+            tap,
+            options,
+            next_index: FUSE_ROOT_ID, // Reserved for the root
         }
     }
 
-    pub fn build(&mut self) -> Result<Vec<Rc<Node>>> {
-        self.build_recursive(0)
-    }
+    /// Build the tree from the archive entries and return its root directory. Entries are placed
+    /// by their full path, so they may appear in any order in the archive.
+    pub fn build(&mut self) -> Result<Rc<Node>> {
+        let mut root = None;
+        // Collect all nodes first and group them by the path of their parent directory
+        let mut nodes = Vec::<Option<(PathBuf, Node)>>::new();
+        let mut children_by_parent = HashMap::<PathBuf, Vec<usize>>::new();
+        let mut paths = HashMap::<PathBuf, usize>::new();
+        let mut versions = HashMap::<PathBuf, u32>::new();
+        let entries = self.archive.entries().context("Failed to read archive")?;
+        for entry in entries {
+            let mut entry = entry.context("Failed to read archive entry")?;
 
-    fn build_recursive(&mut self, level: usize) -> Result<Vec<Rc<Node>>> {
-        let mut nodes = Vec::new();
-        loop {
-            let entry = match self.head.take() {
-                Some(entry) => entry,
-                None => match self.entries.next() {
-                    Some(entry) => entry.context("Failed to read archive entry")?,
-                    None => break,
-                },
+            // The tap recorded the headers of the entry. Extended sparse headers are the only
+            // ones in between the position reported by the entry and the actual data.
+            let (data_offset, ext_headers) = {
+                let mut tap = self.tap.borrow_mut();
+                let headers = std::mem::take(&mut tap.recorded);
+                let ext_size = tap.position - entry.raw_file_position();
+                let ext_headers =
+                    headers[headers.len().saturating_sub(ext_size as usize)..].to_vec();
+                let data_size = match entry.header().entry_type() {
+                    EntryType::GNUSparse => entry.header().entry_size()?,
+                    _ => entry.size(),
+                };
+                tap.record_from = tap.position + data_size.next_multiple_of(512);
+                (tap.position, ext_headers)
             };
+            let sparse = SparseEntry::read(&mut entry, &ext_headers)
+                .context("Failed to read sparse file map")?;
 
-            let path = entry
-                .path()
-                .context("Failed to get path of entry")?
-                .to_str()
-                .context("Entry path is not utf8")?
-                .to_string();
-            let path = Utf8PathBuf::from(canonicalize_entry_path(path));
+            let encoding = self.options.filename_encoding;
+            let raw_path = match sparse.as_ref().and_then(|sparse| sparse.name.as_ref()) {
+                Some(name) => encoding.decode(name),
+                None => encoding.decode(&entry.path_bytes()),
+            };
+            let (path, escaped) = normalize_entry_path(&raw_path);
+            if escaped {
+                let raw_path = Path::new(&raw_path).display();
+                if self.options.strict {
+                    bail!("Entry points outside of the archive root: {raw_path}");
+                }
+                log::warn!(
+                    "Entry points outside of the archive root, placing it at {}: {raw_path}",
+                    path.display(),
+                );
+            } else if !path.as_os_str().is_empty() && path.as_os_str() != trim_entry_path(&raw_path)
+            {
+                log::warn!(
+                    "Normalized entry path to {}: {}",
+                    path.display(),
+                    Path::new(&raw_path).display(),
+                );
+            }
+            let Some(parent) = path.parent().map(PathBuf::from) else {
+                // The archive root itself (e.g. "./") describes the mount point
+                match Node::try_from_entry(&mut entry, FUSE_ROOT_ID, &path, encoding)
+                    .context("Failed to get node for archive entry")?
+                {
+                    Some(node @ Node::Directory { .. }) => root = Some(node),
+                    _ => log::warn!("Skipping archive root entry which is not a directory"),
+                }
+                continue;
+            };
 
             self.next_index += 1;
-            let Some(mut node) = Node::try_from_entry(&entry, self.next_index)
+            let Some(mut node) = Node::try_from_entry(&mut entry, self.next_index, &path, encoding)
                 .context("Failed to get node for archive entry")?
             else {
                 log::warn!(
-                    "Skipping unsupported entry type \"{:?}\" at {path}",
+                    "Skipping unsupported entry type \"{:?}\" at {}",
                     entry.header().entry_type(),
+                    path.display(),
                 );
                 continue;
             };
+            if let (
+                Node::File {
+                    offset,
+                    size,
+                    sparse: map,
+                    ..
+                },
+                Some(sparse),
+            ) = (&mut node, sparse)
+            {
+                *offset = data_offset + sparse.map_size;
+                *size = sparse.size;
+                *map = Some(Rc::new(sparse.map));
+            }
 
-            let entry_level = path
-                .parent()
-                .expect("Entry path has no parent")
-                .components()
-                .count();
-            if entry_level < level {
-                self.head = Some(entry);
-                break;
+            // Later entries replace earlier ones with the same path, e.g. in archives appended to
+            // with `tar -r`. The replaced node keeps its place among its siblings.
+            if let Some(index) = paths.get(&path) {
+                let (_, mut old) = nodes[*index].replace((path.clone(), node)).unwrap();
+                log::debug!("Replacing earlier entry: {}", path.display());
+                if self.options.old_versions && !matches!(old, Node::Directory { .. }) {
+                    let version = versions.entry(path.clone()).or_default();
+                    *version += 1;
+                    let mut name = OsString::from(".");
+                    name.push(old.name());
+                    name.push(format!(".~{version}~"));
+                    let old_path = parent.join(&name);
+                    old.rename(name, old_path.clone().into_os_string());
+                    children_by_parent
+                        .entry(parent)
+                        .or_default()
+                        .push(nodes.len());
+                    paths.insert(old_path.clone(), nodes.len());
+                    nodes.push(Some((old_path, old)));
+                }
+                continue;
             }
+            paths.insert(path.clone(), nodes.len());
 
-            if let Node::Directory { children, .. } = &mut node {
-                children.extend(self.build_recursive(entry_level + 1)?);
+            children_by_parent
+                .entry(parent)
+                .or_default()
+                .push(nodes.len());
+            nodes.push(Some((path, node)));
+        }
+
+        // Synthesize parent directories which are missing from the archive
+        let mut parents = children_by_parent.keys().cloned().collect::<Vec<_>>();
+        parents.sort();
+        for parent in parents {
+            let mut path = parent;
+            while !path.as_os_str().is_empty() && !paths.contains_key(&path) {
+                let parent = path.parent().map(PathBuf::from).unwrap_or_default();
+                log::debug!("Adding missing parent directory: {}", path.display());
+                self.next_index += 1;
+                let node = self.implicit_dir(self.next_index, &path);
+                children_by_parent
+                    .entry(parent.clone())
+                    .or_default()
+                    .push(nodes.len());
+                paths.insert(path.clone(), nodes.len());
+                nodes.push(Some((path, node)));
+                path = parent;
             }
+        }
 
-            nodes.push(Rc::new(node));
+        let children = assemble(&mut nodes, &mut children_by_parent, &PathBuf::new());
+        for (path, _) in nodes.into_iter().flatten() {
+            log::warn!(
+                "Skipping entry whose parent is not a directory: {}",
+                path.display(),
+            );
         }
+        let mut root = root.unwrap_or_else(|| self.implicit_dir(FUSE_ROOT_ID, Path::new("")));
+        if let Node::Directory {
+            children: root_children,
+            ..
+        } = &mut root
+        {
+            *root_children = children;
+        }
+        Ok(Rc::new(root))
+    }
 
-        Ok(nodes)
+    /// Directory at `path` which is missing from the archive
+    fn implicit_dir(&self, index: u64, path: &Path) -> Node {
+        Node::Directory {
+            index,
+            name: path.file_name().unwrap_or_default().to_os_string(),
+            path: path.as_os_str().to_os_string(),
+            mode: self.options.implicit_dir_mode,
+            mtime: self.options.implicit_dir_mtime,
+            atime: self.options.implicit_dir_mtime,
+            ctime: self.options.implicit_dir_mtime,
+            crtime: self.options.implicit_dir_mtime,
+            uid: self.options.implicit_dir_uid,
+            gid: self.options.implicit_dir_gid,
+            uname: None,
+            gname: None,
+            xattrs: Xattrs::default(),
+            acls: Acls::default(),
+            children: Children::default(),
+            implicit: true,
+        }
     }
 }
 
-pub fn canonicalize_entry_path(path: impl AsRef<str>) -> String {
-    path.as_ref()
-        .trim_start_matches('.')
-        .trim_start_matches('/')
-        .to_string()
+/// Move the nodes below `parent` into their directories, recursively.
+fn assemble(
+    nodes: &mut [Option<(PathBuf, Node)>],
+    children_by_parent: &mut HashMap<PathBuf, Vec<usize>>,
+    parent: &Path,
+) -> Children {
+    let Some(indices) = children_by_parent.remove(parent) else {
+        return Children::default();
+    };
+
+    let mut children = Children::default();
+    for index in indices {
+        let Some((path, mut node)) = nodes[index].take() else {
+            continue;
+        };
+        if let Node::Directory { children, .. } = &mut node {
+            *children = assemble(nodes, children_by_parent, &path);
+        }
+        children.push(Rc::new(node));
+    }
+    children
+}
+
+/// Lexically normalize the path of an archive entry. `.` and `..` components are resolved,
+/// duplicate separators are collapsed and leading `/` are stripped like GNU tar does. Also returns
+/// whether `..` components climbed above the archive root.
+pub fn normalize_entry_path(path: impl AsRef<OsStr>) -> (PathBuf, bool) {
+    let mut normalized = PathBuf::new();
+    let mut escaped = false;
+    for component in Path::new(path.as_ref()).components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => escaped |= !normalized.pop(),
+            Component::RootDir | Component::Prefix(_) | Component::CurDir => {}
+        }
+    }
+    (normalized, escaped)
+}
+
+/// Strip leading `./` and trailing `/` from an entry path, which are common in well-formed
+/// archives and not worth reporting when normalizing.
+fn trim_entry_path(path: &OsStr) -> &OsStr {
+    let mut path = path.as_bytes();
+    while let Some(rest) = path.strip_prefix(b"./") {
+        path = rest;
+    }
+    while let Some(rest) = path.strip_suffix(b"/") {
+        path = rest;
+    }
+    OsStr::from_bytes(path)
+}
+
+/// Reader keeping track of its position and recording everything read from a given position on
+struct Tap<R> {
+    inner: R,
+    state: Rc<RefCell<TapState>>,
+}
+
+#[derive(Default)]
+struct TapState {
+    position: u64,
+    record_from: u64,
+    recorded: Vec<u8>,
+}
+
+impl<R: Read> Read for Tap<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        let mut state = self.state.borrow_mut();
+        let start = state.position;
+        state.position += count as u64;
+        if state.position > state.record_from {
+            let skip = state.record_from.saturating_sub(start) as usize;
+            state.recorded.extend_from_slice(&buf[skip..count]);
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_entry_paths() {
+        for (path, normalized, escaped) in [
+            ("a/b", "a/b", false),
+            ("./a/./b/", "a/b", false),
+            ("//a//b/", "a/b", false),
+            ("/etc/passwd", "etc/passwd", false),
+            ("a/../b", "b", false),
+            ("a/b/../..", "", false),
+            ("../x", "x", true),
+            ("a/../../x", "x", true),
+            ("/../x", "x", true),
+        ] {
+            assert_eq!(
+                normalize_entry_path(path),
+                (PathBuf::from(normalized), escaped),
+                "{path}"
+            );
+        }
+    }
 }
@@ -0,0 +1,114 @@
+//! Helpers shared between modules
+
+/// Read a little endian `u32` at `pos` of `buf`
+pub fn u32_at(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+pub mod fixtures {
+    use std::{
+        fs::{create_dir, remove_dir_all},
+        io::Read,
+        path::{Path, PathBuf},
+        time::UNIX_EPOCH,
+    };
+
+    use anyhow::Result;
+
+    use crate::{node::FilenameEncoding, tree::TreeOptions};
+
+    /// Options of trees built by tests, with implicit directories owned by root
+    pub fn tree_options() -> TreeOptions {
+        TreeOptions {
+            implicit_dir_mode: 0o755,
+            implicit_dir_mtime: UNIX_EPOCH,
+            implicit_dir_uid: 0,
+            implicit_dir_gid: 0,
+            old_versions: false,
+            filename_encoding: FilenameEncoding::Raw,
+            strict: false,
+        }
+    }
+
+    /// Compressible but not trivially repetitive data of `len` bytes
+    pub fn test_data(len: usize) -> Vec<u8> {
+        (0..len as u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect()
+    }
+
+    /// Assert that readers returned by `reader_at` yield `data` from the offset to the end
+    ///
+    /// Offsets around each of `boundaries` and every `step` bytes are checked.
+    pub fn assert_reader_at(
+        data: &[u8],
+        boundaries: impl IntoIterator<Item = u64>,
+        step: usize,
+        reader_at: impl Fn(u64) -> Result<Box<dyn Read>>,
+    ) {
+        let mut offsets = vec![0, data.len() - 1];
+        for boundary in boundaries {
+            let offset = boundary as usize;
+            offsets.extend([offset.saturating_sub(1), offset, offset + 1]);
+        }
+        offsets.extend((0..data.len()).step_by(step));
+        for offset in offsets {
+            let mut actual = Vec::new();
+            reader_at(offset as u64)
+                .unwrap()
+                .read_to_end(&mut actual)
+                .unwrap();
+            assert!(actual == data[offset..], "data differs at offset {offset}");
+        }
+    }
+
+    /// Append a PAX extended header holding `records` to `builder`
+    pub fn append_pax_records(builder: &mut tar::Builder<Vec<u8>>, records: &[(&str, &[u8])]) {
+        let mut data = Vec::new();
+        for (key, value) in records {
+            // The length prefix includes itself, the space, the equal sign and the newline
+            let len = key.len() + value.len() + 3;
+            let len = len + (len + len.to_string().len()).to_string().len();
+            data.extend(format!("{len} {key}=").as_bytes());
+            data.extend(*value);
+            data.push(b'\n');
+        }
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(tar::EntryType::XHeader);
+        header.set_size(data.len() as u64);
+        builder
+            .append_data(&mut header, "pax", data.as_slice())
+            .unwrap();
+    }
+
+    /// Temporary directory which is removed with its contents when dropped
+    pub struct TempDir(PathBuf);
+
+    impl TempDir {
+        /// Create a directory unique to this process and `name`
+        pub fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("tarfs-{}-{name}", std::process::id()));
+            let _ = remove_dir_all(&path);
+            create_dir(&path).unwrap();
+            Self(path)
+        }
+
+        pub fn path(&self) -> &Path {
+            &self.0
+        }
+
+        /// Write `data` to the file `name` in the directory.
+        pub fn write(&self, name: &str, data: &[u8]) -> PathBuf {
+            let path = self.0.join(name);
+            std::fs::write(&path, data).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = remove_dir_all(&self.0);
+        }
+    }
+}
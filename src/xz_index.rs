@@ -0,0 +1,302 @@
+use std::{
+    fs::File,
+    io::{self, Cursor, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use flate2::Crc;
+use xz::read::XzDecoder;
+
+use crate::util::u32_at;
+
+/// Size of stream headers and stream footers
+const STREAM_HEADER_SIZE: u64 = 12;
+
+const HEADER_MAGIC: &[u8] = b"\xFD7zXZ\x00";
+const FOOTER_MAGIC: &[u8] = b"YZ";
+
+#[derive(Debug)]
+struct Stream {
+    /// Offset of the stream header
+    offset: u64,
+    /// Offset right after the stream footer
+    end: u64,
+}
+
+#[derive(Debug)]
+struct Block {
+    stream: usize,
+    compressed_offset: u64,
+    decompressed_offset: u64,
+    unpadded_size: u64,
+    uncompressed_size: u64,
+}
+
+/// Block index of an xz file, gathered from the indexes of all of its streams
+#[derive(Debug)]
+pub struct BlockIndex {
+    streams: Vec<Stream>,
+    blocks: Vec<Block>,
+}
+
+impl BlockIndex {
+    /// Read the block index of the xz file at `path`. Returns `None` if the file consists of a
+    /// single block and can't be accessed randomly.
+    pub fn read(path: impl AsRef<Path>) -> Result<Option<Self>> {
+        let mut file = File::open(&path).context("Failed to open archive")?;
+        let mut end = file
+            .metadata()
+            .context("Failed to get archive metadata")?
+            .len();
+
+        // Streams can only be located from their footer, so walk them back to front
+        let mut streams = Vec::new();
+        let mut stream_blocks = Vec::new();
+        ensure!(end % 4 == 0, "Size of xz file is not a multiple of 4");
+        while end > 0 {
+            ensure!(end >= STREAM_HEADER_SIZE, "Truncated xz stream");
+            let mut footer = [0; STREAM_HEADER_SIZE as usize];
+            file.seek(SeekFrom::Start(end - 4))
+                .and_then(|_| file.read_exact(&mut footer[..4]))
+                .context("Failed to read stream footer")?;
+            if footer[..4] == [0; 4] {
+                // Stream padding
+                end -= 4;
+                continue;
+            }
+
+            ensure!(end >= 2 * STREAM_HEADER_SIZE, "Truncated xz stream");
+            file.seek(SeekFrom::Start(end - STREAM_HEADER_SIZE))
+                .and_then(|_| file.read_exact(&mut footer))
+                .context("Failed to read stream footer")?;
+            ensure!(&footer[10..] == FOOTER_MAGIC, "Invalid xz stream footer");
+            let index_size = (u32_at(&footer, 4) as u64 + 1) * 4;
+            let index_offset = (end - STREAM_HEADER_SIZE)
+                .checked_sub(index_size)
+                .context("Truncated xz stream")?;
+
+            let mut index = vec![0; index_size as usize];
+            file.seek(SeekFrom::Start(index_offset))
+                .and_then(|_| file.read_exact(&mut index))
+                .context("Failed to read stream index")?;
+            let records = parse_index(&index).context("Failed to parse stream index")?;
+
+            let blocks_size: u64 = records.iter().map(|(unpadded, _)| padded(*unpadded)).sum();
+            let offset = index_offset
+                .checked_sub(blocks_size + STREAM_HEADER_SIZE)
+                .context("Truncated xz stream")?;
+            let mut header = [0; STREAM_HEADER_SIZE as usize];
+            file.seek(SeekFrom::Start(offset))
+                .and_then(|_| file.read_exact(&mut header))
+                .context("Failed to read stream header")?;
+            ensure!(&header[..6] == HEADER_MAGIC, "Invalid xz stream header");
+
+            streams.push(Stream { offset, end });
+            stream_blocks.push(records);
+            end = offset;
+        }
+        streams.reverse();
+        stream_blocks.reverse();
+
+        let mut blocks = Vec::new();
+        let mut decompressed_offset = 0;
+        for (stream, records) in stream_blocks.into_iter().enumerate() {
+            let mut compressed_offset = streams[stream].offset + STREAM_HEADER_SIZE;
+            for (unpadded_size, uncompressed_size) in records {
+                blocks.push(Block {
+                    stream,
+                    compressed_offset,
+                    decompressed_offset,
+                    unpadded_size,
+                    uncompressed_size,
+                });
+                compressed_offset += padded(unpadded_size);
+                decompressed_offset += uncompressed_size;
+            }
+        }
+
+        if blocks.len() < 2 {
+            return Ok(None);
+        }
+        log::debug!(
+            "Found xz block index with {} blocks in {} streams",
+            blocks.len(),
+            streams.len(),
+        );
+        Ok(Some(Self { streams, blocks }))
+    }
+
+    /// Open a reader at `offset` of the decompressed stream of the xz file at `path`, starting at
+    /// the block which contains the offset.
+    pub fn reader_at(&self, path: impl AsRef<Path>, offset: u64) -> Result<Box<dyn Read>> {
+        let index = self
+            .blocks
+            .partition_point(|block| block.decompressed_offset <= offset);
+        let first = &self.blocks[index.saturating_sub(1)];
+        let stream = &self.streams[first.stream];
+        let blocks = self.blocks[index.saturating_sub(1)..]
+            .iter()
+            .take_while(|block| block.stream == first.stream)
+            .collect::<Vec<_>>();
+        let blocks_end = blocks
+            .last()
+            .map(|block| block.compressed_offset + padded(block.unpadded_size))
+            .unwrap_or(first.compressed_offset);
+
+        // Decoding can only start at a stream header, so splice the blocks of interest into a
+        // new stream with a matching index and keep decoding the following streams as is.
+        let mut file = File::open(&path).context("Failed to open archive")?;
+        let mut header = [0; STREAM_HEADER_SIZE as usize];
+        file.seek(SeekFrom::Start(stream.offset))
+            .and_then(|_| file.read_exact(&mut header))
+            .context("Failed to read stream header")?;
+        let trailer = build_index_and_footer(&blocks, [header[6], header[7]]);
+
+        let mut blocks_file = File::open(&path).context("Failed to open archive")?;
+        blocks_file
+            .seek(SeekFrom::Start(first.compressed_offset))
+            .context("Failed to seek to block")?;
+        file.seek(SeekFrom::Start(stream.end))
+            .context("Failed to seek to stream")?;
+        let spliced = Cursor::new(header)
+            .chain(blocks_file.take(blocks_end - first.compressed_offset))
+            .chain(Cursor::new(trailer))
+            .chain(file);
+
+        let mut decoder = XzDecoder::new_multi_decoder(spliced);
+        io::copy(
+            &mut (&mut decoder).take(offset - first.decompressed_offset),
+            &mut io::sink(),
+        )
+        .context("Failed to skip to offset")?;
+        Ok(Box::new(decoder))
+    }
+}
+
+/// Parse the records (unpadded size and uncompressed size) of a stream index.
+fn parse_index(index: &[u8]) -> Result<Vec<(u64, u64)>> {
+    ensure!(index.len() >= 8, "Index is too short");
+    let (data, crc) = index.split_at(index.len() - 4);
+    ensure!(crc32(data) == u32_at(crc, 0), "Index checksum mismatch");
+    ensure!(data[0] == 0, "Missing index indicator");
+
+    let mut pos = 1;
+    let count = read_vli(data, &mut pos)?;
+    let mut records = Vec::new();
+    for _ in 0..count {
+        let unpadded_size = read_vli(data, &mut pos)?;
+        let uncompressed_size = read_vli(data, &mut pos)?;
+        records.push((unpadded_size, uncompressed_size));
+    }
+    Ok(records)
+}
+
+fn build_index_and_footer(blocks: &[&Block], stream_flags: [u8; 2]) -> Vec<u8> {
+    let mut index = vec![0];
+    write_vli(&mut index, blocks.len() as u64);
+    for block in blocks {
+        write_vli(&mut index, block.unpadded_size);
+        write_vli(&mut index, block.uncompressed_size);
+    }
+    index.resize(index.len().next_multiple_of(4), 0);
+    let crc = crc32(&index);
+    index.extend_from_slice(&crc.to_le_bytes());
+
+    let backward_size = (index.len() / 4 - 1) as u32;
+    let mut footer = Vec::with_capacity(STREAM_HEADER_SIZE as usize);
+    footer.extend_from_slice(&backward_size.to_le_bytes());
+    footer.extend_from_slice(&stream_flags);
+    let crc = crc32(&footer);
+    index.extend_from_slice(&crc.to_le_bytes());
+    index.extend_from_slice(&footer);
+    index.extend_from_slice(FOOTER_MAGIC);
+    index
+}
+
+fn read_vli(buf: &[u8], pos: &mut usize) -> Result<u64> {
+    let mut value = 0;
+    for i in 0..9 {
+        let Some(byte) = buf.get(*pos) else {
+            bail!("Truncated variable length integer");
+        };
+        *pos += 1;
+        value |= ((byte & 0x7F) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("Variable length integer is too long")
+}
+
+fn write_vli(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn padded(unpadded_size: u64) -> u64 {
+    unpadded_size.next_multiple_of(4)
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use xz::{stream::MtStreamBuilder, write::XzEncoder};
+
+    use super::*;
+    use crate::util::fixtures::{assert_reader_at, test_data, TempDir};
+
+    /// Compress `data` as an xz stream of blocks of at most `block_size` bytes
+    fn xz_stream(data: &[u8], block_size: u64) -> Vec<u8> {
+        let stream = MtStreamBuilder::new()
+            .threads(1)
+            .block_size(block_size)
+            .preset(1)
+            .encoder()
+            .unwrap();
+        let mut encoder = XzEncoder::new_stream(Vec::new(), stream);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn reader_at_matches_full_decode() {
+        let data = test_data(2_400_000);
+        let (first, second) = data.split_at(1_000_000);
+        let mut xz = xz_stream(first, 300_000);
+        xz.extend([0; 8]);
+        xz.extend(xz_stream(second, 500_000));
+        xz.extend([0; 4]);
+        let dir = TempDir::new("xz-reader-at");
+        let path = dir.write("multi.xz", &xz);
+
+        let index = BlockIndex::read(&path).unwrap().unwrap();
+        assert_eq!(index.streams.len(), 2);
+        assert_eq!(index.blocks.len(), 4 + 3);
+
+        let boundaries = index.blocks.iter().map(|b| b.decompressed_offset);
+        assert_reader_at(&data, boundaries, 99_991, |offset| {
+            index.reader_at(&path, offset)
+        });
+    }
+
+    #[test]
+    fn invalid_sizes_are_rejected() {
+        let xz = xz_stream(b"data", 1024);
+        let dir = TempDir::new("xz-invalid");
+        for size in [1, 3, 6, 8, xz.len() - 1] {
+            let path = dir.write(&format!("truncated-{size}.xz"), &xz[xz.len() - size..]);
+            assert!(BlockIndex::read(&path).is_err(), "size {size} accepted");
+        }
+    }
+}
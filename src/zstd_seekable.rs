@@ -0,0 +1,185 @@
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::util::u32_at;
+
+/// Magic number of skippable frames holding a seek table
+const SKIPPABLE_MAGIC: u32 = 0x184D2A5E;
+
+/// Magic number at the very end of a seek table
+const SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+
+/// Size of the skippable frame header (magic and frame size)
+const SKIPPABLE_HEADER_SIZE: u64 = 8;
+
+/// Size of the seek table footer (number of frames, descriptor and magic)
+const FOOTER_SIZE: u64 = 9;
+
+/// Descriptor flag signaling that each seek table entry carries a checksum
+const CHECKSUM_FLAG: u8 = 1 << 7;
+
+#[derive(Debug)]
+struct Frame {
+    compressed_offset: u64,
+    decompressed_offset: u64,
+}
+
+/// Seek table of an archive in the zstd seekable format
+#[derive(Debug)]
+pub struct SeekTable {
+    frames: Vec<Frame>,
+}
+
+impl SeekTable {
+    /// Read the seek table at the end of the zstd file at `path`. Returns `None` if the file is a
+    /// plain zstd file without a seek table.
+    pub fn read(path: impl AsRef<Path>) -> Result<Option<Self>> {
+        let mut file = File::open(&path).context("Failed to open archive")?;
+        let file_size = file
+            .metadata()
+            .context("Failed to get archive metadata")?
+            .len();
+        if file_size < SKIPPABLE_HEADER_SIZE + FOOTER_SIZE {
+            return Ok(None);
+        }
+
+        let mut footer = [0; FOOTER_SIZE as usize];
+        file.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))
+            .and_then(|_| file.read_exact(&mut footer))
+            .context("Failed to read seek table footer")?;
+        if u32_at(&footer, 5) != SEEKABLE_MAGIC {
+            return Ok(None);
+        }
+        let frame_count = u32_at(&footer, 0) as u64;
+        let descriptor = footer[4];
+        let entry_size = if descriptor & CHECKSUM_FLAG != 0 {
+            12
+        } else {
+            8
+        };
+
+        let table_size = frame_count * entry_size + FOOTER_SIZE;
+        let Some(data_size) = file_size.checked_sub(SKIPPABLE_HEADER_SIZE + table_size) else {
+            bail!("Seek table is larger than the archive");
+        };
+        let mut table = vec![0; (SKIPPABLE_HEADER_SIZE + table_size) as usize];
+        file.seek(SeekFrom::Start(data_size))
+            .and_then(|_| file.read_exact(&mut table))
+            .context("Failed to read seek table")?;
+        if u32_at(&table, 0) != SKIPPABLE_MAGIC || u32_at(&table, 4) as u64 != table_size {
+            bail!("Seek table is not wrapped in a skippable frame");
+        }
+
+        let mut frames = Vec::with_capacity(frame_count as usize);
+        let mut compressed_offset = 0;
+        let mut decompressed_offset = 0;
+        for entry in table[SKIPPABLE_HEADER_SIZE as usize..]
+            .chunks_exact(entry_size as usize)
+            .take(frame_count as usize)
+        {
+            frames.push(Frame {
+                compressed_offset,
+                decompressed_offset,
+            });
+            compressed_offset += u32_at(entry, 0) as u64;
+            decompressed_offset += u32_at(entry, 4) as u64;
+        }
+        if compressed_offset != data_size {
+            bail!("Seek table does not match the size of the archive");
+        }
+
+        log::debug!("Found zstd seek table with {frame_count} frames");
+        Ok(Some(Self { frames }))
+    }
+
+    /// Open a reader at `offset` of the decompressed stream of the zstd file at `path`, starting
+    /// at the frame which contains the offset.
+    pub fn reader_at(&self, path: impl AsRef<Path>, offset: u64) -> Result<Box<dyn Read>> {
+        let index = self
+            .frames
+            .partition_point(|frame| frame.decompressed_offset <= offset);
+        let frame = index
+            .checked_sub(1)
+            .map(|index| &self.frames[index])
+            .context("Seek table has no frames")?;
+
+        let mut file = File::open(&path).context("Failed to open archive")?;
+        file.seek(SeekFrom::Start(frame.compressed_offset))
+            .context("Failed to seek to frame")?;
+        let mut decoder = zstd::Decoder::new(file).context("Failed to create zstd decoder")?;
+        io::copy(
+            &mut (&mut decoder).take(offset - frame.decompressed_offset),
+            &mut io::sink(),
+        )
+        .context("Failed to skip to offset")?;
+        Ok(Box::new(decoder))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::fixtures::{assert_reader_at, test_data, TempDir};
+
+    /// Compress each chunk of `data` into its own frame and append a seek table
+    fn seekable(data: &[u8], frame_size: usize, checksums: bool) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        let mut entries = Vec::new();
+        for chunk in data.chunks(frame_size) {
+            let frame = zstd::encode_all(chunk, 1).unwrap();
+            entries.extend((frame.len() as u32).to_le_bytes());
+            entries.extend((chunk.len() as u32).to_le_bytes());
+            if checksums {
+                entries.extend(0u32.to_le_bytes());
+            }
+            compressed.extend(frame);
+        }
+        let frame_count = data.len().div_ceil(frame_size) as u32;
+        let descriptor = if checksums { CHECKSUM_FLAG } else { 0 };
+
+        compressed.extend(SKIPPABLE_MAGIC.to_le_bytes());
+        compressed.extend((entries.len() as u32 + FOOTER_SIZE as u32).to_le_bytes());
+        compressed.extend(entries);
+        compressed.extend(frame_count.to_le_bytes());
+        compressed.push(descriptor);
+        compressed.extend(SEEKABLE_MAGIC.to_le_bytes());
+        compressed
+    }
+
+    #[test]
+    fn reader_at_matches_data() {
+        let data = test_data(1_200_000);
+        let frame_size = 100_000;
+        let dir = TempDir::new("zstd-reader-at");
+        for checksums in [false, true] {
+            let path = dir.write(
+                &format!("seekable-{checksums}.zst"),
+                &seekable(&data, frame_size, checksums),
+            );
+            let seek_table = SeekTable::read(&path).unwrap().unwrap();
+            assert_eq!(seek_table.frames.len(), data.len().div_ceil(frame_size));
+
+            let boundaries = seek_table.frames.iter().map(|f| f.decompressed_offset);
+            assert_reader_at(&data, boundaries, 77_777, |offset| {
+                seek_table.reader_at(&path, offset)
+            });
+        }
+    }
+
+    #[test]
+    fn plain_and_invalid_files() {
+        let dir = TempDir::new("zstd-invalid");
+        let path = dir.write("plain.zst", &zstd::encode_all(&[0; 1000][..], 1).unwrap());
+        assert!(SeekTable::read(&path).unwrap().is_none());
+
+        let mut data = seekable(&[0; 1000], 100, false);
+        data.remove(0);
+        let path = dir.write("mismatched.zst", &data);
+        assert!(SeekTable::read(&path).is_err());
+    }
+}